@@ -0,0 +1,549 @@
+//! Federates with the fediverse (Mastodon and the like), over ActivityPub.
+//!
+//! Every user is an actor, `/ap/user/<access_hash>` (found by WebFinger as `<access_hash>@<host>`), whose outbox lists the listed posts they created. Every listed post is an object, `/ap/post/<id>`: an `Article` if it's top-level, else a `Note` in reply to its parent.
+//! Inboxes take `Follow`s, `Create`s of notes that reply to posts here (which become replies here), and `Like`s (which become +1 rewards), and `Undo`s of those follows and likes.
+//! Remote actors become users here, with an unlisted first post that links to them, and an access hash that no access token hashes to.
+//!
+//! Incoming activities must have a valid HTTP signature by their actor. Sending (`Accept`s of follows, and new posts to followers) needs the site's RSA key, as PEM in the `ACTIVITYPUB_PRIVATE_KEY` environment variable: without it, nothing is sent, and actors have no public key. (All actors share that key.)
+//! Edits aren't sent out, but moves to other parents are (as `Update`s, since their `inReplyTo` changes).
+
+
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use crate::posts_api::{content_hash, new_uuid, site_url, CanPost, Expiry, Post, Publishing, Settings, Visibility};
+use crate::posts_helpers::markdown_to_html;
+use crate::posts_store::Database;
+use crate::outbound;
+
+use curl::easy::List;
+use iron::url::Url;
+use openssl::base64;
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::sign::{Signer, Verifier};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, from_str, json, Value};
+
+
+
+/// The media type of ActivityPub documents.
+pub const CONTENT_TYPE: &str = "application/activity+json";
+
+const CONTEXT: &str = "https://www.w3.org/ns/activitystreams";
+const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+const PAGE_LEN: usize = 20;
+
+/// The most that's read of another server's response.
+const MAX_RESPONSE: usize = 1024 * 1024;
+
+/// How far (in seconds) a signed request's `Date` may be from now, so that old requests can't be replayed.
+const MAX_CLOCK_SKEW: i64 = 5 * 60;
+
+
+
+/// Someone on another server who follows a user here, under `ap_followers/<access_hash>/<hash of their actor ID>`.
+#[derive(Serialize, Deserialize)]
+struct Follower {
+    actor: String,
+    inbox: String,
+}
+/// A remote actor that became a user here, under `ap_actors/<access_hash>`.
+#[derive(Serialize, Deserialize)]
+struct RemoteActor {
+    id: String,
+    inbox: String,
+}
+
+
+
+fn actor_url(access_hash: &str) -> String {
+    format!("{}/ap/user/{}", site_url(), access_hash)
+}
+fn object_url(post_id: &str) -> String {
+    format!("{}/ap/post/{}", site_url(), post_id)
+}
+
+/// The site's signing key, if there is one.
+fn private_key() -> Option<PKey<Private>> {
+    let pem = std::env::var("ACTIVITYPUB_PRIVATE_KEY").ok()?;
+    Rsa::private_key_from_pem(pem.as_bytes()).ok().map(|k| PKey::from_rsa(k).ok()).flatten()
+}
+
+/// The access hash of a remote actor. (Access tokens are hashed with a salt, so none of them hashes to this.)
+fn remote_hash(actor_id: &str) -> String {
+    content_hash(format!("activitypub {}", actor_id).as_bytes())
+}
+
+/// The first post of a user of this site (not a remote actor), if there is such a user.
+fn local_first_post(data: &Database, access_hash: &str) -> Option<Post> {
+    let first_post_id = data.get_first_post(access_hash)()?;
+    let r = data.at(&["ap_actors", access_hash]).ok().map(|n| n.get().ok()).flatten();
+    if r.map(|r| from_str::<Option<RemoteActor>>(&r.body).ok()).flatten().flatten().is_some() { return None };
+    data.read(vec![&first_post_id]).pop().flatten()
+}
+
+/// An ID that's either a string or an object with an `id`.
+fn id_of(v: &Value) -> &str {
+    v.as_str().or_else(|| v["id"].as_str()).unwrap_or("")
+}
+
+
+
+/// Answers a WebFinger query for `acct:<access_hash>@<host>`.
+pub fn webfinger(data: &Database, resource: &str) -> Option<Value> {
+    let host = Url::parse(&site_url()).ok()?.host_str()?.to_owned();
+    let access_hash = resource.strip_prefix("acct:")?.strip_suffix(&format!("@{}", host))?;
+    local_first_post(data, access_hash)?;
+    let profile = format!("{}/user/{}", site_url(), access_hash);
+    Some(json!({
+        "subject": resource,
+        "aliases": [profile, actor_url(access_hash)],
+        "links": [
+            { "rel": "self", "type": CONTENT_TYPE, "href": actor_url(access_hash) },
+            { "rel": "http://webfinger.net/rel/profile-page", "type": "text/html", "href": profile },
+        ],
+    }))
+}
+
+/// Describes a user as a `Person`, named by the first line of their first post.
+pub fn actor(data: &Database, access_hash: &str) -> Option<Value> {
+    let first_post = local_first_post(data, access_hash)?;
+    let id = actor_url(access_hash);
+    let mut actor = json!({
+        "@context": [CONTEXT, "https://w3id.org/security/v1"],
+        "type": "Person",
+        "id": id,
+        "preferredUsername": access_hash,
+        "name": first_post.content.lines().next().unwrap_or("").trim_start_matches('#').trim(),
+        "url": format!("{}/user/{}", site_url(), access_hash),
+        "inbox": format!("{}/inbox", id),
+        "outbox": format!("{}/outbox", id),
+        "followers": format!("{}/followers", id),
+        "endpoints": { "sharedInbox": format!("{}/ap/inbox", site_url()) },
+    });
+    if let Some(key) = private_key() {
+        actor["publicKey"] = json!({
+            "id": format!("{}#main-key", id),
+            "owner": id,
+            "publicKeyPem": String::from_utf8(key.public_key_to_pem().unwrap()).unwrap(),
+        });
+    }
+    Some(actor)
+}
+
+/// Counts a user's followers on other servers (which followers are isn't shown).
+pub fn followers(data: &Database, access_hash: &str) -> Option<Value> {
+    local_first_post(data, access_hash)?;
+    let r = data.at(&["ap_followers", access_hash]).ok().map(|n| n.with_params().shallow(true).get().ok()).flatten();
+    let count = r.map(|r| from_str::<HashMap<String, bool>>(&r.body).ok()).flatten().map(|m| m.len()).unwrap_or(0);
+    Some(json!({
+        "@context": CONTEXT,
+        "type": "OrderedCollection",
+        "id": format!("{}/followers", actor_url(access_hash)),
+        "totalItems": count,
+    }))
+}
+
+/// Lists the listed posts that a user created, newest first, as `Create` activities: the collection, or (with `page`) one page of it.
+pub fn outbox(data: &Database, access_hash: &str, page: Option<usize>) -> Option<Value> {
+    local_first_post(data, access_hash)?;
+    let id = format!("{}/outbox", actor_url(access_hash));
+    let page = match page {
+        Some(page) => page,
+        None => return Some(json!({
+            "@context": CONTEXT,
+            "type": "OrderedCollection",
+            "id": id,
+            "totalItems": Post::get_listed_count(data, access_hash),
+            "first": format!("{}?page=0", id),
+        })),
+    };
+    let ids = Post::get_listed_by(data, access_hash, page * PAGE_LEN, (page + 1) * PAGE_LEN + 1);
+    let more = ids.len() > PAGE_LEN;
+    let ids: Vec<String> = ids.into_iter().take(PAGE_LEN).collect();
+    let items: Vec<Value> = data.read(ids.iter().map(|s| &s[..]).collect()).iter().flatten().filter_map(|post| note(data, post)).map(create).collect();
+    let mut collection = json!({
+        "@context": CONTEXT,
+        "type": "OrderedCollectionPage",
+        "id": format!("{}?page={}", id, page),
+        "partOf": id,
+        "orderedItems": items,
+    });
+    if more { collection["next"] = json!(format!("{}?page={}", id, page + 1)) };
+    Some(collection)
+}
+
+/// Describes a listed post as an `Article` (if it's top-level, titled by its first line) or a `Note`.
+pub fn object(data: &Database, post_id: &str) -> Option<Value> {
+    note(data, &data.read(vec![post_id]).pop().flatten()?)
+}
+fn note(data: &Database, post: &Post) -> Option<Value> {
+    let json = post.to_json_sync(data, None);
+    let parent_id = json["parent_id"].as_str().unwrap_or("");
+    if json["draft"] == json!(true) || json["visibility"] != json!("public") || json["burn_after_reading"] == json!(true) || post.id == parent_id { return None };
+    // Posts that came from elsewhere are only theirs to describe.
+    if remote_object_of(data, &post.id).is_some() { return None };
+    // Nor are replies to what not everyone can read.
+    if parent_id != "" && !data.read(vec![parent_id]).pop().flatten().map(|p| p.visible_to("", "")).unwrap_or(false) { return None };
+    let mut note = json!({
+        "@context": CONTEXT,
+        "type": "Note",
+        "id": object_url(&post.id),
+        "url": format!("{}{}", site_url(), json["human_readable_url"].as_str().unwrap_or("")),
+        "published": chrono::NaiveDateTime::from_timestamp(json["created"].as_i64().unwrap_or(0), 0).format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+        "to": [PUBLIC],
+        "content": markdown_to_html(&post.id, &post.content),
+        "tag": post.tags.iter().map(|t| json!({ "type": "Hashtag", "name": format!("#{}", t), "href": format!("{}/tag/{}", site_url(), t) })).collect::<Vec<Value>>(),
+    });
+    if parent_id == "" {
+        let (title, rest) = post.content.split_once('\n').unwrap_or((&post.content, ""));
+        note["type"] = json!("Article");
+        note["name"] = json!(title.trim_start_matches('#').trim());
+        note["content"] = json!(markdown_to_html(&post.id, rest));
+    } else {
+        note["inReplyTo"] = json!(remote_object_of(data, parent_id).unwrap_or_else(|| object_url(parent_id)));
+    }
+    if post.access_hash != "" {
+        note["attributedTo"] = json!(actor_url(&post.access_hash));
+        note["cc"] = json!([format!("{}/followers", actor_url(&post.access_hash))]);
+    }
+    Some(note)
+}
+fn create(note: Value) -> Value {
+    json!({
+        "@context": CONTEXT,
+        "type": "Create",
+        "id": format!("{}#create", id_of(&note)),
+        "actor": note["attributedTo"],
+        "published": note["published"],
+        "to": note["to"],
+        "cc": note["cc"],
+        "object": note,
+    })
+}
+
+fn update(note: Value) -> Value {
+    json!({
+        "@context": CONTEXT,
+        "type": "Update",
+        "id": format!("{}#update-{}", id_of(&note), new_uuid()),
+        "actor": note["attributedTo"],
+        "to": note["to"],
+        "cc": note["cc"],
+        "object": note,
+    })
+}
+
+/// Sends a new post to its author's followers on other servers, in the background. Does nothing without the site's key.
+pub fn publish(data: &Database, post: &Post) {
+    send_to_followers(data, post, create)
+}
+/// Sends a post that changed where it is (its `inReplyTo`) to its author's followers on other servers, like `publish`.
+pub fn republish(data: &Database, post: &Post) {
+    send_to_followers(data, post, update)
+}
+fn send_to_followers(data: &Database, post: &Post, activity: fn(Value) -> Value) {
+    if post.access_hash == "" || private_key().is_none() { return };
+    let r = data.at(&["ap_followers", &post.access_hash]).ok().map(|n| n.get().ok()).flatten();
+    let followers = r.map(|r| from_str::<HashMap<String, Follower>>(&r.body).ok()).flatten().unwrap_or_default();
+    if followers.is_empty() { return };
+    if let Some(note) = note(data, post) {
+        let activity = activity(note);
+        let inboxes: HashSet<String> = followers.into_values().map(|f| f.inbox).collect(); // Shared inboxes, once each.
+        for inbox in inboxes { deliver(inbox, activity.clone(), &post.access_hash) }
+    }
+}
+
+
+
+/// Takes an activity that was POSTed to an inbox (at `path`), if it's signed by its actor.
+pub fn receive(data: &Database, path: &str, headers: &HashMap<String, String>, body: &[u8]) -> Result<(), String> {
+    let actor = verify(&format!("post {}", path), headers, body)?;
+    let activity: Value = from_slice(body).map_err(|e| e.to_string())?;
+    let actor_id = actor["id"].as_str().ok_or("The actor has no ID")?;
+    if id_of(&activity["actor"]) != actor_id { return Err("The activity isn't by who signed it".to_owned()) };
+    let object = &activity["object"];
+    match (activity["type"].as_str().unwrap_or(""), object["type"].as_str().unwrap_or("")) {
+        ("Follow", _) => follow(data, &actor, &activity),
+        ("Undo", "Follow") => {
+            let access_hash = id_of(&object["object"]).trim_start_matches(&actor_url("")).to_owned();
+            data.at(&["ap_followers", &access_hash, &content_hash(actor_id.as_bytes())]).ok().map(|n| n.delete("").ok());
+            Ok(())
+        },
+        ("Create", "Note") | ("Create", "Article") => reply(data, &actor, object),
+        ("Like", _) => like(data, &actor, id_of(object), 1),
+        ("Undo", "Like") => like(data, &actor, id_of(&object["object"]), 0),
+        _ => Ok(()), // Nothing else means anything here.
+    }
+}
+
+/// Checks an incoming request's HTTP signature (and that the body is what was signed), and returns the actor who signed it.
+fn verify(request_target: &str, headers: &HashMap<String, String>, body: &[u8]) -> Result<Value, String> {
+    let e = |e: openssl::error::ErrorStack| e.to_string();
+    let digest = format!("SHA-256={}", base64::encode_block(&hash(MessageDigest::sha256(), body).map_err(e)?));
+    if headers.get("digest") != Some(&digest) { return Err("The body doesn't match its digest".to_owned()) };
+    let signature = headers.get("signature").ok_or("Not signed")?;
+    let params: HashMap<&str, &str> = signature.split(',').filter_map(|p| p.split_once('=')).map(|(k, v)| (k.trim(), v.trim().trim_matches('"'))).collect();
+    let key_id = params.get("keyId").ok_or("No keyId in the signature")?;
+    let signed_headers = params.get("headers").copied().unwrap_or("date");
+    // What's signed must tie the signature to this request, this body and this moment.
+    for required in ["(request-target)", "digest", "date"] {
+        if !signed_headers.split(' ').any(|h| h == required) { return Err(format!("`{}` isn't signed", required)) };
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(headers.get("date").ok_or("No `date` header")?).map_err(|e| e.to_string())?;
+    if (chrono::Utc::now().timestamp() - date.timestamp()).abs() > MAX_CLOCK_SKEW { return Err("The request is too old (or too new)".to_owned()) };
+    let signing_string = signed_headers.split(' ').map(|h| match h {
+        "(request-target)" => Ok(format!("(request-target): {}", request_target)),
+        h => headers.get(h).map(|v| format!("{}: {}", h, v)).ok_or(format!("No `{}` header", h)),
+    }).collect::<Result<Vec<String>, String>>()?.join("\n");
+
+    let key_doc = fetch(key_id)?;
+    let key = if key_doc["publicKey"].is_object() { &key_doc["publicKey"] } else { &key_doc };
+    let public_key = PKey::public_key_from_pem(key["publicKeyPem"].as_str().unwrap_or("").as_bytes()).map_err(e)?;
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &public_key).map_err(e)?;
+    verifier.update(signing_string.as_bytes()).map_err(e)?;
+    let signature = base64::decode_block(params.get("signature").ok_or("No signature")?).map_err(e)?;
+    if !verifier.verify(&signature).map_err(e)? { return Err("Bad signature".to_owned()) };
+    // The key must be the one that its owner says is theirs, or anyone could sign as anyone.
+    let owner = id_of(&key["owner"]);
+    let actor = if id_of(&key_doc) == owner { key_doc.clone() } else { fetch(owner)? };
+    if id_of(&actor) != owner || id_of(&actor["publicKey"]) != *key_id { return Err("The key isn't its owner's".to_owned()) };
+    Ok(actor)
+}
+
+/// Remembers a remote follower of a user here, and accepts.
+fn follow(data: &Database, actor: &Value, activity: &Value) -> Result<(), String> {
+    let access_hash = id_of(&activity["object"]).strip_prefix(&actor_url("")).ok_or("Not one of our users")?;
+    local_first_post(data, access_hash).ok_or("No such user")?;
+    let follower = Follower{
+        actor: id_of(actor).to_owned(),
+        inbox: actor["endpoints"]["sharedInbox"].as_str().or_else(|| actor["inbox"].as_str()).ok_or("The actor has no inbox")?.to_owned(),
+    };
+    data.at(&["ap_followers", access_hash, &content_hash(follower.actor.as_bytes())]).ok().map(|n| n.set(&json!(follower).to_string()).ok());
+    let accept = json!({
+        "@context": CONTEXT,
+        "type": "Accept",
+        "id": format!("{}#accept-{}", actor_url(access_hash), new_uuid()),
+        "actor": actor_url(access_hash),
+        "object": activity,
+    });
+    deliver(actor["inbox"].as_str().unwrap_or(&follower.inbox).to_owned(), accept, access_hash);
+    Ok(())
+}
+
+/// Adds a public remote reply to a post here that anyone can read (or to a reply that came from elsewhere), by the remote actor.
+fn reply(data: &Database, actor: &Value, object: &Value) -> Result<(), String> {
+    let object_id = id_of(object);
+    if id_of(&object["attributedTo"]) != id_of(actor) { return Err("The note isn't by who sent it".to_owned()) };
+    if local_post_of(data, object_id).is_some() { return Ok(()) }; // Already here.
+    let parent_id = public_post_of(data, id_of(&object["inReplyTo"])).ok_or("Not a reply to anything here")?.id;
+    let addressed = |v: &Value| v == PUBLIC || v.as_array().map(|a| a.iter().any(|v| v == PUBLIC)).unwrap_or(false);
+    if !addressed(&object["to"]) && !addressed(&object["cc"]) { return Ok(()) }; // Not for everyone to see.
+    let access_hash = remote_user(data, actor)?;
+    let content = format!("{}\n\n*— [{}]({})*", html_to_text(object["content"].as_str().unwrap_or("")), handle(actor), object["url"].as_str().unwrap_or(object_id));
+    let mut child_id = None;
+    data.update(vec![&parent_id], |mut posts| match posts.remove(0) {
+        Some(parent) => {
+            let (parent, child) = Post::new(data, parent, &access_hash, content, Settings{ children_rights: CanPost::All, publishing: Publishing::Now, expiry: Expiry::Never, visibility: Visibility::Public });
+            child_id = child.as_ref().map(|c| c.id.clone());
+            vec![Some(parent), child]
+        },
+        None => vec![],
+    });
+    let child_id = child_id.ok_or("The post doesn't take replies")?;
+    data.at(&["ap_objects", &child_id]).ok().map(|n| n.set(&json!(object_id).to_string()).ok());
+    Ok(())
+}
+
+/// Rewards a post here that anyone can read, from a remote actor (`amount` being 1 for a like, and 0 to take it back).
+fn like(data: &Database, actor: &Value, object_id: &str, amount: i8) -> Result<(), String> {
+    let post_id = public_post_of(data, object_id).ok_or("Not a post here")?.id;
+    let access_hash = remote_user(data, actor)?;
+    let first_post_id = data.get_first_post(&access_hash)().ok_or("The actor has no first post")?;
+    data.update(vec![&post_id, &first_post_id], |mut posts| {
+        if posts.iter().any(|p| p.is_none()) { return vec![] };
+        let (post, first_post) = (posts.remove(0).unwrap(), posts.remove(0).unwrap());
+        let (first_post, maybe_post) = post.reward(data, first_post, amount);
+        vec![Some(first_post), maybe_post]
+    });
+    Ok(())
+}
+
+/// Makes a remote actor a user here, if they aren't yet: their first post, unlisted, links to them. Returns their access hash.
+fn remote_user(data: &Database, actor: &Value) -> Result<String, String> {
+    let actor_id = id_of(actor);
+    let access_hash = remote_hash(actor_id);
+    if data.get_first_post(&access_hash)().is_some() { return Ok(access_hash) };
+    let remote = RemoteActor{ id: actor_id.to_owned(), inbox: actor["inbox"].as_str().unwrap_or("").to_owned() };
+    data.at(&["ap_actors", &access_hash]).ok().map(|n| n.set(&json!(remote).to_string()).ok());
+    let name = actor["name"].as_str().filter(|n| !n.trim().is_empty()).map(|n| n.to_owned()).unwrap_or_else(|| handle(actor));
+    let content = format!("# {}\n\nOn the fediverse: [{}]({})", name.replace('\n', " "), handle(actor), actor["url"].as_str().unwrap_or(actor_id));
+    data.update(vec![""], |mut posts| match posts.remove(0) {
+        Some(root) => {
+            let (root, first_post) = Post::new(data, root, &access_hash, content, Settings{ children_rights: CanPost::None, publishing: Publishing::Now, expiry: Expiry::Never, visibility: Visibility::Unlisted });
+            vec![Some(root), first_post]
+        },
+        None => vec![],
+    });
+    Ok(access_hash)
+}
+
+/// `@name@host`, or the actor's ID if it has no name.
+fn handle(actor: &Value) -> String {
+    let host = Url::parse(id_of(actor)).ok().map(|u| u.host_str().map(|h| h.to_owned())).flatten();
+    match (actor["preferredUsername"].as_str(), host) {
+        (Some(name), Some(host)) => format!("@{}@{}", name, host),
+        _ => id_of(actor).to_owned(),
+    }
+}
+
+/// Finds the post here that an object ID means: one of ours, or a remote one that was copied here.
+fn local_post_of(data: &Database, object_id: &str) -> Option<String> {
+    if let Some(post_id) = object_id.strip_prefix(&object_url("")) {
+        return data.read(vec![post_id]).pop().flatten().map(|p| p.id)
+    }
+    let response = data.at(&["ap_objects"]).ok().map(|n| {
+        // Needs a `".indexOn": ".value"` rule on `"ap_objects"`.
+        let mut n = n.with_params();
+        let url = std::sync::Arc::get_mut(&mut n.url).unwrap();
+        url.set_query(Some(&format!("orderBy={}&equalTo={}", "\"$value\"", json!(object_id).to_string().replace('&', "%26"))));
+        n.get().ok()
+    }).flatten();
+    let copies = response.map(|r| from_str::<HashMap<String, String>>(&r.body).ok()).flatten().unwrap_or_default();
+    copies.into_keys().next()
+}
+
+/// Finds the post here that an object ID means, like `local_post_of`, if anyone can read it.
+fn public_post_of(data: &Database, object_id: &str) -> Option<Post> {
+    data.read(vec![&local_post_of(data, object_id)?]).pop().flatten().filter(|p| p.visible_to("", ""))
+}
+
+/// The ID of the remote object that a post was copied from, if it was.
+fn remote_object_of(data: &Database, post_id: &str) -> Option<String> {
+    let r = data.at(&["ap_objects", post_id]).ok().map(|n| n.get().ok()).flatten();
+    r.map(|r| from_str::<Option<String>>(&r.body).ok()).flatten().flatten()
+}
+
+/// Turns the HTML of a remote post into plain text, since its markup is neither trusted nor needed.
+fn html_to_text(html: &str) -> String {
+    let html = html.replace("<br>", "\n").replace("<br/>", "\n").replace("<br />", "\n").replace("</p>", "\n\n");
+    let mut text = String::new();
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => (),
+        }
+    }
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&#39;", "'").replace("&amp;", "&").trim().to_owned()
+}
+
+
+
+/// POSTs an activity to a remote inbox, signed as a user here, in the background. Does nothing without the site's key.
+fn deliver(inbox: String, activity: Value, access_hash: &str) {
+    if private_key().is_none() { return };
+    let key_id = format!("{}#main-key", actor_url(access_hash));
+    std::thread::spawn(move || {
+        let body = activity.to_string();
+        if let Err(e) = send(&inbox, Some(&body), Some(&key_id)) {
+            eprintln!("Could not deliver to {}: {}", inbox, e);
+        }
+    });
+}
+
+/// GETs an ActivityPub document from another server. (Unsigned, so servers that only answer signed requests won't.)
+fn fetch(url: &str) -> Result<Value, String> {
+    send(url, None, None)
+}
+/// GETs (or, with a `body`, POSTs) to another server, signed with the site's key as `key_id` (if given, and if there is a key), and returns the response (`null` if empty).
+fn send(url: &str, body: Option<&str>, key_id: Option<&str>) -> Result<Value, String> {
+    let e = |e: curl::Error| e.to_string();
+    let parsed = Url::parse(url).map_err(|e| e.to_string())?;
+    if parsed.scheme() != "https" && parsed.scheme() != "http" { return Err(format!("Not a web address: {}", url)) };
+    let host = match parsed.port() {
+        Some(port) => format!("{}:{}", parsed.host_str().unwrap_or(""), port),
+        None => parsed.host_str().unwrap_or("").to_owned(),
+    };
+    let target = match parsed.query() {
+        Some(query) => format!("{}?{}", parsed.path(), query),
+        None => parsed.path().to_owned(),
+    };
+    let date = chrono::Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+    let mut headers = List::new();
+    let mut signed = vec![
+        format!("(request-target): {} {}", if body.is_some() { "post" } else { "get" }, target),
+        format!("host: {}", host),
+        format!("date: {}", date),
+    ];
+    headers.append(&format!("Accept: {}, application/ld+json", CONTENT_TYPE)).map_err(e)?;
+    headers.append("User-Agent: text_sharing_site").map_err(e)?;
+    headers.append(&format!("Host: {}", host)).map_err(e)?;
+    headers.append(&format!("Date: {}", date)).map_err(e)?;
+    if let Some(body) = body {
+        let digest = format!("SHA-256={}", base64::encode_block(&hash(MessageDigest::sha256(), body.as_bytes()).map_err(|e| e.to_string())?));
+        headers.append(&format!("Content-Type: {}", CONTENT_TYPE)).map_err(e)?;
+        headers.append(&format!("Digest: {}", digest)).map_err(e)?;
+        signed.push(format!("digest: {}", digest));
+    }
+    if let (Some(key_id), Some(key)) = (key_id, private_key()) {
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).map_err(|e| e.to_string())?;
+        signer.update(signed.join("\n").as_bytes()).map_err(|e| e.to_string())?;
+        let signature = base64::encode_block(&signer.sign_to_vec().map_err(|e| e.to_string())?);
+        let names: Vec<&str> = signed.iter().map(|h| h.split_once(':').unwrap().0).collect();
+        headers.append(&format!("Signature: keyId=\"{}\",algorithm=\"rsa-sha256\",headers=\"{}\",signature=\"{}\"", key_id, names.join(" "), signature)).map_err(e)?;
+    }
+
+    let mut easy = outbound::open(url)?;
+    if let Some(body) = body {
+        easy.post(true).map_err(e)?;
+        easy.post_fields_copy(body.as_bytes()).map_err(e)?;
+    }
+    easy.http_headers(headers).map_err(e)?;
+    easy.timeout(Duration::from_secs(10)).map_err(e)?;
+    let mut response = Vec::new();
+    {
+        let mut transfer = easy.transfer();
+        transfer.write_function(|chunk| {
+            if response.len() + chunk.len() > MAX_RESPONSE { return Ok(0) }; // Aborts.
+            response.extend_from_slice(chunk);
+            Ok(chunk.len())
+        }).map_err(e)?;
+        transfer.perform().map_err(e)?;
+    }
+    let status = easy.response_code().map_err(e)?;
+    if status / 100 != 2 { return Err(format!("{} answered {}", url, status)) };
+    if response.is_empty() { return Ok(Value::Null) };
+    from_slice(&response).map_err(|e| e.to_string())
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The headers of a request with `body`, signed (badly) over `signed` and dated `date`.
+    fn headers(body: &[u8], signed: &str, date: &str) -> HashMap<String, String> {
+        let digest = format!("SHA-256={}", base64::encode_block(&hash(MessageDigest::sha256(), body).unwrap()));
+        let signature = format!("keyId=\"https://example.com/actor#main-key\",headers=\"{}\",signature=\"AAAA\"", signed);
+        [("digest", digest), ("signature", signature), ("date", date.to_owned()), ("host", "example.com".to_owned())].into_iter().map(|(k, v)| (k.to_owned(), v)).collect()
+    }
+
+    #[test]
+    fn refuses_unbound_signatures() {
+        let now = chrono::Utc::now().to_rfc2822();
+        let verified = |h: &HashMap<String, String>| verify("post /ap/inbox", h, b"{}");
+        assert_eq!(verified(&headers(b"{}", "host date digest", &now)).unwrap_err(), "`(request-target)` isn't signed");
+        assert_eq!(verified(&headers(b"{}", "(request-target) host date", &now)).unwrap_err(), "`digest` isn't signed");
+        assert_eq!(verified(&headers(b"{}", "(request-target) host digest", &now)).unwrap_err(), "`date` isn't signed");
+        assert_eq!(verified(&headers(b"{\"a\":1}", "(request-target) host date digest", &now)).unwrap_err(), "The body doesn't match its digest");
+        let old = (chrono::Utc::now() - chrono::Duration::seconds(MAX_CLOCK_SKEW + 60)).to_rfc2822();
+        assert_eq!(verified(&headers(b"{}", "(request-target) host date digest", &old)).unwrap_err(), "The request is too old (or too new)");
+    }
+}
\ No newline at end of file
@@ -0,0 +1,144 @@
+//! Stores files that are attached to posts, on the local disk.
+//!
+//! Files are named by the hash of their content, so re-uploading one doesn't duplicate it, and their URLs never change meaning.
+
+
+
+use std::io::Cursor;
+use std::path::PathBuf;
+
+use crate::posts_api::content_hash;
+
+use image::{ImageFormat, ImageOutputFormat, DynamicImage};
+use image::io::Reader;
+
+
+
+/// Images bigger than this (in either dimension) are shown through a thumbnail.
+const THUMBNAIL_SIZE: u32 = 320;
+
+/// Images with more pixels than this are refused, because decoding them would take too much memory.
+const MAX_PIXELS: u64 = 50_000_000;
+
+
+
+/// Why a file was not stored.
+#[derive(Debug)]
+pub enum Rejection {
+    TooLarge,
+    UnsupportedType,
+    Unreadable,
+}
+
+
+
+/// A file that was checked (and re-encoded), as it'll be stored.
+pub struct Upload {
+    /// Markdown that refers to it.
+    pub markdown: String,
+    files: Vec<(String, Vec<u8>)>, // (name, bytes) of the file, and of its thumbnail if it has one.
+}
+
+/// The directory of attachments, and its limits.
+pub struct Attachments {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+impl Attachments {
+    /// Configures from the environment: `ATTACHMENTS_DIR` (`"attachments"` by default) and `ATTACHMENTS_MAX_SIZE` (in bytes, 5 MiB by default).
+    pub fn from_env() -> Attachments {
+        let dir = std::env::var("ATTACHMENTS_DIR").unwrap_or_else(|_| "attachments".to_owned());
+        let max_size = std::env::var("ATTACHMENTS_MAX_SIZE").ok().map(|s| s.parse::<u64>().ok()).flatten().unwrap_or(5 * 1024 * 1024);
+        std::fs::create_dir_all(&dir).unwrap();
+        Attachments{ dir: PathBuf::from(dir), max_size }
+    }
+
+    /// Checks an uploaded file, and makes it ready to be stored, along with Markdown that refers to it (an image, or a link).
+    ///
+    /// Accepts PNG/JPEG/GIF/WebP images, PDFs, and UTF-8 text. Images (except GIFs, which carry no EXIF) are re-encoded, which strips their metadata, such as the location where a photo was taken.
+    /// Nothing is written until `store`, so that files of posts that turn out to be rejected aren't kept.
+    pub fn prepare(&self, file: &params::File) -> Result<Upload, Rejection> {
+        if file.size > self.max_size { return Err(Rejection::TooLarge) };
+        let bytes = std::fs::read(&file.path).map_err(|_| Rejection::Unreadable)?;
+        if bytes.len() as u64 > self.max_size { return Err(Rejection::TooLarge) };
+        let title = file.filename.as_deref().unwrap_or("").replace(|c: char| "[]()\\<>\n\r".contains(c), "");
+        let (bytes, ext, thumbnail) = match image::guess_format(&bytes).ok() {
+            Some(format @ (ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Gif | ImageFormat::WebP)) => {
+                let reader = Reader::new(Cursor::new(&bytes)).with_guessed_format().map_err(|_| Rejection::Unreadable)?;
+                let (w, h) = reader.into_dimensions().map_err(|_| Rejection::Unreadable)?;
+                if (w as u64) * (h as u64) > MAX_PIXELS { return Err(Rejection::TooLarge) };
+                let img = image::load_from_memory(&bytes).map_err(|_| Rejection::Unreadable)?;
+                let thumbnail = if w > THUMBNAIL_SIZE || h > THUMBNAIL_SIZE { Some(img.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)) } else { None };
+                match format {
+                    ImageFormat::Gif => (bytes, "gif", thumbnail),
+                    ImageFormat::Jpeg => (encode(&img, ImageOutputFormat::Jpeg(90))?, "jpg", thumbnail),
+                    _ => (encode(&img, ImageOutputFormat::Png)?, "png", thumbnail),
+                }
+            },
+            _ if bytes.starts_with(b"%PDF-") => (bytes, "pdf", None),
+            _ if std::str::from_utf8(&bytes).is_ok() && !bytes.contains(&0u8) => (bytes, "txt", None),
+            _ => return Err(Rejection::UnsupportedType),
+        };
+        let name = content_hash(&bytes) + "." + ext;
+        let url = "/attachment/".to_owned() + &name;
+        Ok(match (ext, thumbnail) {
+            ("pdf", _) | ("txt", _) => Upload{ markdown: format!("[{}]({})", if title == "" { &name } else { &title }, url), files: vec![(name, bytes)] },
+            (_, None) => Upload{ markdown: format!("![{}]({})", title, url), files: vec![(name, bytes)] },
+            (_, Some(thumbnail)) => {
+                let thumb_name = content_hash(&bytes) + ".thumb.jpg";
+                let thumb = encode(&thumbnail, ImageOutputFormat::Jpeg(80))?;
+                Upload{ markdown: format!("[![{}](/attachment/{})]({})", title, thumb_name, url), files: vec![(name, bytes), (thumb_name, thumb)] }
+            },
+        })
+    }
+
+    /// Stores a file that `prepare` made ready (and its thumbnail).
+    pub fn store(&self, upload: &Upload) -> Result<(), Rejection> {
+        for (name, bytes) in &upload.files {
+            std::fs::write(self.dir.join(name), bytes).map_err(|_| Rejection::Unreadable)?;
+        }
+        Ok(())
+    }
+
+    /// Gets the path to a stored file, and its MIME type, by the name in its URL.
+    /// Only names that `store` could have produced are accepted, so this can't reach outside the directory.
+    pub fn path(&self, name: &str) -> Option<(PathBuf, &'static str)> {
+        let (hash, ext) = name.split_once('.')?;
+        if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) { return None };
+        let mime = match ext {
+            "png" => "image/png",
+            "jpg" | "thumb.jpg" => "image/jpeg",
+            "gif" => "image/gif",
+            "pdf" => "application/pdf",
+            "txt" => "text/plain; charset=utf-8",
+            _ => return None,
+        };
+        let path = self.dir.join(name);
+        if path.is_file() { Some((path, mime)) } else { None }
+    }
+}
+
+
+
+fn encode(img: &DynamicImage, format: ImageOutputFormat) -> Result<Vec<u8>, Rejection> {
+    let mut out = vec![];
+    match format {
+        // JPEG has no transparency.
+        ImageOutputFormat::Jpeg(_) => DynamicImage::ImageRgb8(img.to_rgb8()).write_to(&mut out, format),
+        _ => img.write_to(&mut out, format),
+    }.map_err(|_| Rejection::Unreadable)?;
+    Ok(out)
+}
+
+
+
+impl ToString for Rejection {
+    fn to_string(&self) -> String {
+        match self {
+            Self::TooLarge => "The file is too large",
+            Self::UnsupportedType => "Only images, PDFs and text files can be attached",
+            Self::Unreadable => "The file could not be processed",
+        }.to_string()
+    }
+}
@@ -6,7 +6,14 @@ use std::sync::Arc;
 mod posts_api;
 mod posts_store;
 mod posts_helpers;
-use posts_api::{Post, CanPost};
+mod attachments;
+mod webhooks;
+mod activitypub;
+mod webmention;
+mod sitemap;
+mod outbound;
+use posts_api::{Post, CanPost, Expiry, Leaving, Publishing, Settings, Visibility, MAX_CONTENT, MAX_TAGS};
+use posts_store::Resolved;
 
 extern crate iron;
 extern crate staticfile;
@@ -23,6 +30,7 @@ use iron::modifiers::Header;
 use iron::error::IronError;
 use iron::status;
 use iron::modifiers::RedirectRaw;
+use iron::response::WriteBody;
 use params::{Params, Value};
 use staticfile::Static;
 use handlebars::Handlebars;
@@ -32,22 +40,65 @@ use firebase_rs::Firebase;
 
 
 
+/// Parts of the `User-Agent`s (lowercase) of programs that fetch pages without anyone reading them (yet): crawlers, and link previews in chats and social media.
+const ROBOTS: [&str; 10] = ["bot", "crawl", "spider", "preview", "facebookexternalhit", "whatsapp", "mastodon", "embedly", "curl", "wget"];
+
+
+
 fn main() {
     let files = Static::new(Path::new("static"));
     // Register Handlebars templates, from the `static` directory.
+    // (And remember all names that paths may start with, so that no post can take them as its URL.)
     let mut templates = Handlebars::new();
+    let mut reserved_urls: Vec<String> = ["login", "new", "edit", "move", "publish", "reward", "save", "subscribe", "follow", "events", "webhook", "ap", "webmention", "sitemap", "takeout", "delete_account", "attachment"].iter().map(|s| s.to_string()).collect();
     for path in std::fs::read_dir("static").unwrap() {
         let path = path.unwrap().path();
         let full_path = path.to_str().unwrap();
         let filename = path.file_name().unwrap().to_str().unwrap();
+        reserved_urls.push(filename.to_owned());
         if filename.ends_with(".html") {
             let name = &filename[..filename.len()-5];
             templates.register_template_file(name, full_path).unwrap();
+            reserved_urls.push(name.to_owned());
         }
     }
 
     let firebase = Firebase::new("https://text-sharing-site-default-rtdb.europe-west1.firebasedatabase.app/").unwrap();
     let data = Arc::new(posts_store::Database::new(firebase));
+
+    // Maintenance commands, run instead of the server. (Before anything is written, so that `import` can see an empty database.)
+    let path_arg = std::env::args().nth(2);
+    match std::env::args().nth(1).as_deref() {
+        Some("reslug") => return data.reslug_all(), // After changing how human-readable URLs are made.
+        Some("export") => { // To a file, or to stdout.
+            let r = match path_arg {
+                Some(path) => std::fs::File::create(path).map_err(|e| e.to_string()).map(|f| data.export(std::io::BufWriter::new(f))).and_then(|r| r),
+                None => data.export(std::io::stdout().lock()),
+            };
+            if let Err(e) = r { eprintln!("{}", e); std::process::exit(1) };
+            return
+        },
+        Some("check") => { // With `--fix`, also repairs.
+            match data.check_consistency(path_arg.as_deref() == Some("--fix")) {
+                Ok(0) => println!("No problems found."),
+                Ok(n) => println!("{} problems found.", n),
+                Err(e) => { eprintln!("{}", e); std::process::exit(1) },
+            }
+            return
+        },
+        Some("import") => { // From a file, or from stdin.
+            let r = match path_arg {
+                Some(path) => std::fs::File::open(path).map_err(|e| vec![e.to_string()]).map(|f| data.import(std::io::BufReader::new(f))).and_then(|r| r),
+                None => data.import(std::io::stdin().lock()),
+            };
+            if let Err(problems) = r {
+                for p in problems { eprintln!("{}", p) };
+                std::process::exit(1)
+            };
+            return
+        },
+        _ => (),
+    }
     data.update(vec![""], |v: Vec<Option<Post>>| {
         if v[0].is_none() {
             println!("Creating the initial post.");
@@ -77,19 +128,29 @@ That's all you need to know. Good luck.
         } else { vec![None] }
     });
     posts_helpers::PostHelper::register(&mut templates, &data);
+    let attachments = attachments::Attachments::from_env();
 
 
 
     let templates = templates;
-    let render = |templates: &Handlebars, name: &str, user: &str, post_id: &str, page:u64| {
+    let render = |templates: &Handlebars, name: &str, user: &str, post_id: &str, page:u64, key: &str| {
         let body = templates.render(name, &json!({
             "user": user,
             "post": post_id,
             "page": page,
+            "key": key,
             "max_depth": 1,
         })).unwrap();
         Ok(Response::with((mime!(Text/Html), status::Ok, body)))
     };
+    // Publish scheduled drafts when they're due, and erase expired posts.
+    let data_for_schedule = data.clone();
+    std::thread::spawn(move || loop {
+        Post::publish_due(&data_for_schedule);
+        Post::sweep_expired(&data_for_schedule);
+        std::thread::sleep(std::time::Duration::from_secs(60));
+    });
+    let data_for_streams = data.clone();
     let chain = Chain::new(move |req: &mut Request| -> IronResult<Response> {
         // Get the `user=…` cookie. (It's a whole big process. The `cookie` library is questionably designed.)
         let cookie = req.headers.get::<iron::headers::Cookie>();
@@ -102,6 +163,14 @@ That's all you need to know. Good luck.
             },
             None => "".to_string(),
         };
+        // The key from a private post's share link.
+        let key = req.url.as_ref().query_pairs().find(|(k, _)| k == "key").map(|(_, v)| v.into_owned()).unwrap_or_default();
+        // Whether this is someone reading a page (which burns posts that burn after reading), rather than link previews, crawlers and the like fetching it.
+        let reading = req.method == iron::method::Get && req.headers.get::<headers::UserAgent>().map(|agent| {
+            let agent = agent.to_lowercase();
+            !ROBOTS.iter().any(|robot| agent.contains(robot))
+        }).unwrap_or(false);
+        let key_query = if key == "" { "".to_owned() } else { format!("?key={}", iron::url::form_urlencoded::byte_serialize(key.as_bytes()).collect::<String>()) };
         // Actually handle the request, exposing the POST API.
         let get = |map: &params::Map, key: &str| -> Option<String> {
             let v = map.get(key);
@@ -119,6 +188,37 @@ That's all you need to know. Good luck.
                 response: Response::with((status::BadRequest, "Bad data")),
             })
         };
+        let rejected = |why: String| {
+            Err(IronError{
+                error: Box::new(std::io::Error::new(std::io::ErrorKind::InvalidInput, why.clone())),
+                response: Response::with((status::BadRequest, why)),
+            })
+        };
+        // Checks the files uploaded as `attachments[]`, and appends references to them to the content. They're stored by `attachments.store`, once the post is known to be accepted.
+        let attach = |map: &params::Map, content: String| -> Result<(String, Vec<attachments::Upload>), attachments::Rejection> {
+            let files = match map.get("attachments") {
+                Some(Value::Array(files)) => files.iter().collect(),
+                Some(file) => vec![file],
+                None => vec![],
+            };
+            let uploads = files.iter().filter_map(|file| match file {
+                Value::File(f) if f.size > 0 => Some(attachments.prepare(f)),
+                _ => None,
+            }).collect::<Result<Vec<attachments::Upload>, attachments::Rejection>>()?;
+            let content = uploads.iter().fold(content, |content, upload| content + "\n\n" + &upload.markdown);
+            Ok((content, uploads))
+        };
+        let too_long = || rejected(format!("The post is too long: it can have up to {} bytes (including its attachments' links)", MAX_CONTENT));
+        // Collects tags from the `tags` field (separated by commas or spaces) and from `#hashtags` in the content.
+        let tags = |map: &params::Map, content: &str| -> Vec<String> {
+            let field = get(map, "tags").unwrap_or_default();
+            let typed = field.split(|c: char| c == ',' || c.is_whitespace()).map(|t| t.to_owned());
+            let mut tags: Vec<String> = vec![];
+            for tag in typed.chain(posts_helpers::hashtags(content)).filter_map(|t| posts_api::normalize_tag(&t)) {
+                if !tags.contains(&tag) && tags.len() < MAX_TAGS { tags.push(tag) };
+            }
+            tags
+        };
         let not_logged_in = || {
             Err(IronError{
                 error: Box::new(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "Not logged in")),
@@ -135,7 +235,7 @@ That's all you need to know. Good luck.
         let elsewhere = status::SeeOther;
         match req.url.path()[..] {
             [""] => {
-                render(&templates, "post", &user, "", 0)
+                render(&templates, "post", &user, "", 0, "")
             },
             ["login"] => { // url, user
                 // It's unclear how the `params` crate deals with too-large requests.
@@ -160,7 +260,7 @@ That's all you need to know. Good luck.
                     _ => fail(url.unwrap_or_else(|| "".to_owned())),
                 }
             },
-            ["new"] => { // url, parent_id, content, rights, user
+            ["new"] => { // url, parent_id, content, rights, user, slug, when, publish_at, expires, visibility, tags
                 // This might be the longest implementation of a simple behavior I've ever seen.
                 //   And it's not even very efficient.
                 //   Rust (and static typing in particular) forces a lot of boilerplate.
@@ -170,16 +270,59 @@ That's all you need to know. Good luck.
                 let (url, parent_id, content, rights, user) = (get(map, "url"), get(map, "parent_id"), get(map, "content"), get(map, "rights"), get(map, "user"));
                 if parent_id.is_none() || content.is_none() || rights.is_none() || user.is_none() { return fail() };
                 let (parent_id, content, rights, user) = (parent_id.unwrap(), content.unwrap(), rights.unwrap(), user.unwrap());
+                let slug = get(map, "slug").unwrap_or_default();
+                if slug != "" {
+                    if let Err(r) = data.check_url(&slug, None, &reserved_urls) { return rejected(r.to_string()) };
+                }
+                let publishing = match (get(map, "when").as_deref(), get(map, "publish_at")) {
+                    (None, _) | (Some("now"), _) => Publishing::Now,
+                    (Some("draft"), _) => Publishing::Draft,
+                    // From `<input type="datetime-local">`, in UTC.
+                    (Some("at"), Some(at)) => match chrono::NaiveDateTime::parse_from_str(&at, "%Y-%m-%dT%H:%M") {
+                        Ok(at) => Publishing::At(at.timestamp()),
+                        Err(_) => return rejected("The publication time is not a valid date and time".to_owned()),
+                    },
+                    _ => return fail(),
+                };
+                let expiry = match get(map, "expires").as_deref() {
+                    None | Some("never") => Expiry::Never,
+                    Some("view") => Expiry::FirstView,
+                    Some(seconds) => match seconds.parse::<i64>() {
+                        Ok(seconds) if seconds > 0 => Expiry::After(seconds),
+                        _ => return fail(),
+                    },
+                };
+                let visibility = match get(map, "visibility").as_deref() {
+                    None | Some("public") => Visibility::Public,
+                    Some("unlisted") => Visibility::Unlisted,
+                    Some("private") => Visibility::Private,
+                    _ => return fail(),
+                };
                 if let Ok(rights) = rights.parse::<CanPost>() {
+                    let token = crate::posts_api::access_token_hash(&user);
+                    match data.read(vec![&parent_id]).pop().flatten() {
+                        Some(parent) if parent.accepts_child(&token) => (),
+                        _ => return fail(),
+                    }
+                    if content.len() > MAX_CONTENT { return too_long() };
+                    let (content, uploads) = match attach(map, content) { Ok(c) => c, Err(r) => return rejected(r.to_string()) };
+                    if content.len() > MAX_CONTENT { return too_long() };
+                    // Only now, so that rejected posts leave no files behind.
+                    if let Err(r) = uploads.iter().try_for_each(|u| attachments.store(u)) { return rejected(r.to_string()) };
+                    let tags = tags(map, &content);
                     let maybe_first_post_id = data.login(&user)();
                     let was_logged_in = maybe_first_post_id.is_some();
                     let ids: Vec<&str> = vec![&parent_id];
                     data.update(ids, |mut posts| {
                         if posts[0].is_none() { return vec![] };
                         let parent = posts.remove(0).unwrap();
-                        let token = crate::posts_api::access_token_hash(&user);
-                        let r = Post::new(&data, parent, &token, content, rights);
+                        let r = Post::new(&data, parent, &token, content, Settings{ children_rights: rights, publishing, expiry, visibility });
                         let (parent, maybe_child) = r;
+                        let maybe_child = maybe_child.map(|mut child| {
+                            if slug != "" { child.human_readable_url = slug.clone(); child.custom_url = true };
+                            child.tags = tags;
+                            child
+                        });
                         vec![Some(parent), maybe_child]
                     });
                     let url = url.unwrap_or_else(|| "/".to_string());
@@ -192,17 +335,38 @@ That's all you need to know. Good luck.
                     fail()
                 }
             },
-            ["edit"] => { // url, post_id, content, rights
+            ["edit"] => { // url, post_id, content, rights, slug, tags
                 let map = req.get_ref::<Params>();
                 if map.is_err() { return fail() };
                 let map = map.unwrap();
                 let (url, post_id, content, rights) = (get(map, "url"), get(map, "post_id"), get(map, "content"), get(map, "rights"));
                 if post_id.is_none() || content.is_none() || rights.is_none() { return fail() };
                 let (post_id, content, rights) = (post_id.unwrap(), content.unwrap(), rights.unwrap());
+                let slug = get(map, "slug").unwrap_or_default();
+                if slug != "" {
+                    if let Err(r) = data.check_url(&slug, Some(&post_id), &reserved_urls) { return rejected(r.to_string()) };
+                }
                 if let Ok(rights) = rights.parse::<CanPost>() {
+                    match data.read(vec![&post_id]).pop().flatten() {
+                        Some(post) if post.access_hash != "" && post.access_hash == posts_api::access_token_hash(&user) => (),
+                        _ => return fail(),
+                    }
+                    if content.len() > MAX_CONTENT { return too_long() };
+                    let (content, uploads) = match attach(map, content) { Ok(c) => c, Err(r) => return rejected(r.to_string()) };
+                    if content.len() > MAX_CONTENT { return too_long() };
+                    // Only now, so that rejected edits leave no files behind.
+                    if let Err(r) = uploads.iter().try_for_each(|u| attachments.store(u)) { return rejected(r.to_string()) };
+                    let tags = tags(map, &content);
                     data.update(vec![&post_id], |mut posts| {
                         match posts.remove(0) {
-                            Some(post) => vec![post.edit(&user, content, rights)],
+                            Some(post) => {
+                                let post = post.edit(&user, content, rights);
+                                vec![post.map(|mut p| {
+                                    if slug != "" && slug != p.human_readable_url { p.human_readable_url = slug.clone(); p.custom_url = true };
+                                    p.tags = tags;
+                                    p
+                                })]
+                            },
                             None => vec![],
                         }
                     });
@@ -212,6 +376,42 @@ That's all you need to know. Good luck.
                     fail()
                 }
             },
+            ["move"] => { // url, post_id, parent (an ID or a URL)
+                let map = req.get_ref::<Params>();
+                if map.is_err() { return fail() };
+                let map = map.unwrap();
+                let (url, post_id, parent) = (get(map, "url"), get(map, "post_id"), get(map, "parent"));
+                if post_id.is_none() || parent.is_none() { return fail() };
+                let (post_id, parent) = (post_id.unwrap(), parent.unwrap());
+                let parent = parent.trim().trim_end_matches('/').rsplit('/').next().unwrap_or("").to_owned();
+                let parent_id = match data.resolve_url(&parent) {
+                    Some(Resolved::Post(id)) => id,
+                    _ => parent,
+                };
+                data.update(vec![&post_id, &parent_id], |mut posts| {
+                    if posts.iter().any(|p| p.is_none()) { return vec![] };
+                    let (post, parent) = (posts.remove(0).unwrap(), posts.remove(0).unwrap());
+                    let (moved, notice) = post.move_to(&data, &parent, &user);
+                    vec![moved, notice]
+                });
+                let url = url.unwrap_or_else(|| "/".to_string());
+                Ok(Response::with((elsewhere, RedirectRaw(url))))
+            },
+            ["publish"] => { // url, post_id
+                let map = req.get_ref::<Params>();
+                if map.is_err() { return fail() };
+                let map = map.unwrap();
+                let (url, post_id) = (get(map, "url"), get(map, "post_id"));
+                if post_id.is_none() { return fail() };
+                let post_id = post_id.unwrap();
+                let token = crate::posts_api::access_token_hash(&user);
+                data.update(vec![&post_id], |mut posts| match posts.remove(0) {
+                    Some(post) if post.access_hash == token && post.access_hash != "" => vec![Some(post.publish(&data))],
+                    _ => vec![],
+                });
+                let url = url.unwrap_or_else(|| "/".to_string());
+                Ok(Response::with((elsewhere, RedirectRaw(url))))
+            },
             ["reward"] => { // url, post_id, amount
                 let map = req.get_ref::<Params>();
                 if map.is_err() { return fail() };
@@ -237,19 +437,236 @@ That's all you need to know. Good luck.
                     fail()
                 }
             },
+            ["save"] => { // url, post_id, saved, [key]
+                let map = req.get_ref::<Params>();
+                if map.is_err() { return fail() };
+                let map = map.unwrap();
+                let (url, post_id, saved) = (get(map, "url"), get(map, "post_id"), get(map, "saved"));
+                if post_id.is_none() { return fail() };
+                let post_id = post_id.unwrap();
+                let key = get(map, "key").unwrap_or_default();
+                match data.login(&user)() {
+                    Some(first_post_id) => {
+                        data.read(vec![&post_id]).pop().flatten().map(|post| post.save(&data, &user, &first_post_id, &key, saved.as_deref() != Some("0")));
+                        let url = url.unwrap_or_else(|| "/".to_string());
+                        Ok(Response::with((elsewhere, RedirectRaw(url))))
+                    },
+                    None => not_logged_in(),
+                }
+            },
+            ["subscribe"] => { // url, post_id, subscribed, [key]
+                let map = req.get_ref::<Params>();
+                if map.is_err() { return fail() };
+                let map = map.unwrap();
+                let (url, post_id, subscribed) = (get(map, "url"), get(map, "post_id"), get(map, "subscribed"));
+                if post_id.is_none() { return fail() };
+                let post_id = post_id.unwrap();
+                let key = get(map, "key").unwrap_or_default();
+                match data.login(&user)() {
+                    Some(first_post_id) => {
+                        data.read(vec![&post_id]).pop().flatten().map(|post| post.subscribe(&data, &user, &first_post_id, &key, subscribed.as_deref() != Some("0")));
+                        let url = url.unwrap_or_else(|| "/".to_string());
+                        Ok(Response::with((elsewhere, RedirectRaw(url))))
+                    },
+                    None => not_logged_in(),
+                }
+            },
+            ["follow"] => { // url, access_hash, following
+                let map = req.get_ref::<Params>();
+                if map.is_err() { return fail() };
+                let map = map.unwrap();
+                let (url, access_hash, following) = (get(map, "url"), get(map, "access_hash"), get(map, "following"));
+                if access_hash.is_none() { return fail() };
+                match data.login(&user)() {
+                    Some(first_post_id) => {
+                        Post::follow(&data, &user, &first_post_id, &access_hash.unwrap(), following.as_deref() != Some("0"));
+                        let url = url.unwrap_or_else(|| "/".to_string());
+                        Ok(Response::with((elsewhere, RedirectRaw(url))))
+                    },
+                    None => not_logged_in(),
+                }
+            },
+            ["events", post_id] => { // A `text/event-stream` of `child`, `edit` and `reward` events, each with JSON data (see `Post::announce`).
+                let post_id = match data.resolve_url(post_id) {
+                    Some(Resolved::Moved(url)) => return Ok(Response::with((status::MovedPermanently, RedirectRaw(format!("/events/{}{}", url, key_query))))),
+                    Some(Resolved::Post(id)) => id,
+                    None => post_id.to_string(),
+                };
+                match data.read(vec![&post_id]).pop().flatten() {
+                    Some(post) if post.visible_to(&user, &key) => match data.listen(&post_id) {
+                        Some(stream) => {
+                            let mut res = Response::with(("text/event-stream".parse::<Mime>().unwrap(), status::Ok, Box::new(stream) as Box<dyn WriteBody>));
+                            res.headers.set(headers::CacheControl(vec![headers::CacheDirective::NoCache]));
+                            Ok(res)
+                        },
+                        None => Ok(Response::with((status::ServiceUnavailable, "Too many listeners, try again later"))),
+                    },
+                    _ => Ok(Response::with((status::NotFound, "No such post"))),
+                }
+            },
+            ["webhook"] => { // url, post_id, action (add|remove|ping), [hook_url], [hook_id]
+                let map = req.get_ref::<Params>();
+                if map.is_err() { return fail() };
+                let map = map.unwrap();
+                let (url, post_id, action) = (get(map, "url"), get(map, "post_id"), get(map, "action"));
+                if post_id.is_none() || action.is_none() { return fail() };
+                let post_id = post_id.unwrap();
+                match data.read(vec![&post_id]).pop().flatten() {
+                    Some(post) if post.access_hash != "" && post.access_hash == posts_api::access_token_hash(&user) => (),
+                    _ => return fail(),
+                }
+                let hook_id = get(map, "hook_id").unwrap_or_default();
+                match action.as_deref() {
+                    Some("add") => match webhooks::add(&data, &post_id, &get(map, "hook_url").unwrap_or_default()) {
+                        Ok(_) => (),
+                        Err(r) => return rejected(r.to_string()),
+                    },
+                    Some("remove") => webhooks::remove(&data, &post_id, &hook_id),
+                    Some("ping") => webhooks::trigger(&data, &post_id, "ping", json!({ "post_id": post_id }), Some(&hook_id)),
+                    _ => return fail(),
+                }
+                let url = url.unwrap_or_else(|| "/".to_string());
+                Ok(Response::with((elsewhere, RedirectRaw(url))))
+            },
+            ["webmention"] => { // source, target: another site's page, and the post that it links to.
+                if req.method != iron::method::Post { return Ok(Response::with(status::MethodNotAllowed)) };
+                // The client is who connected, unless `TRUST_PROXY` says that's a proxy (like Heroku's router), in which case it's the address that the proxy added last (the others could be made up).
+                let forwarded = std::env::var("TRUST_PROXY").ok().and(req.headers.get_raw("X-Forwarded-For")).and_then(|v| v.last()).map(|v| String::from_utf8_lossy(v).rsplit(',').next().unwrap_or("").trim().to_owned());
+                let client = forwarded.unwrap_or_else(|| req.remote_addr.ip().to_string());
+                if !webmention::admit(&client) { return Ok(Response::with((status::TooManyRequests, "Too many Webmentions, try again later"))) };
+                let map = req.get_ref::<Params>();
+                if map.is_err() { return fail() };
+                let map = map.unwrap();
+                match webmention::receive(&data, &get(map, "source").unwrap_or_default(), &get(map, "target").unwrap_or_default()) {
+                    Ok(()) => Ok(Response::with(status::Accepted)),
+                    Err(e) => Ok(Response::with((status::BadRequest, e))),
+                }
+            },
+            [".well-known", "webfinger"] => {
+                let resource = req.url.as_ref().query_pairs().find(|(k, _)| k == "resource").map(|(_, v)| v.into_owned()).unwrap_or_default();
+                match activitypub::webfinger(&data, &resource) {
+                    Some(jrd) => Ok(Response::with(("application/jrd+json".parse::<Mime>().unwrap(), status::Ok, jrd.to_string()))),
+                    None => Ok(Response::with((status::NotFound, "No such user"))),
+                }
+            },
+            ["ap", "inbox"] | ["ap", "user", _, "inbox"] => { // ActivityPub activities, signed by their actors.
+                if req.method != iron::method::Post { return Ok(Response::with(status::MethodNotAllowed)) };
+                let headers: std::collections::HashMap<String, String> = req.headers.iter().map(|h| (h.name().to_lowercase(), h.value_string())).collect();
+                let path = format!("/{}", req.url.path().join("/"));
+                let mut body = vec![];
+                if std::io::Read::read_to_end(&mut std::io::Read::take(&mut req.body, 1024 * 1024), &mut body).is_err() { return fail() };
+                match activitypub::receive(&data, &path, &headers, &body) {
+                    Ok(()) => Ok(Response::with(status::Accepted)),
+                    Err(e) => Ok(Response::with((status::BadRequest, e))),
+                }
+            },
+            ["ap", "user", access_hash] | ["ap", "user", access_hash, _] | ["ap", "post", access_hash] => {
+                let page = req.url.as_ref().query_pairs().find(|(k, _)| k == "page").map(|(_, v)| v.parse::<usize>().ok()).flatten();
+                let document = match &req.url.path()[..] {
+                    [_, "post", _] => activitypub::object(&data, access_hash),
+                    [_, _, _, "outbox"] => activitypub::outbox(&data, access_hash, page),
+                    [_, _, _, "followers"] => activitypub::followers(&data, access_hash),
+                    [_, _, _] => activitypub::actor(&data, access_hash),
+                    _ => None,
+                };
+                match document {
+                    Some(document) => Ok(Response::with((activitypub::CONTENT_TYPE.parse::<Mime>().unwrap(), status::Ok, document.to_string()))),
+                    None => Ok(Response::with((status::NotFound, "Not found"))),
+                }
+            },
+            ["takeout"] => match data.takeout(&user) { // All of the user's own data, as a JSON download.
+                Some(takeout) => {
+                    let mut res = Response::with((mime!(Application/Json), status::Ok, takeout.to_string()));
+                    res.headers.set_raw("Content-Disposition", vec![b"attachment; filename=\"takeout.json\"".to_vec()]);
+                    Ok(res)
+                },
+                None => not_logged_in(),
+            },
+            ["delete_account"] => { // posts, confirm
+                let map = req.get_ref::<Params>();
+                if map.is_err() { return fail() };
+                let map = map.unwrap();
+                let leaving = match get(map, "posts").as_deref() {
+                    Some("erase") => Leaving::Erase,
+                    Some("anonymize") => Leaving::Anonymize,
+                    _ => return fail(),
+                };
+                if get(map, "confirm").as_deref() != Some("on") { return fail() };
+                if Post::delete_account(&data, &user, leaving) {
+                    Ok(Response::with((elsewhere, login_cookie(""), RedirectRaw("/".to_owned()))))
+                } else {
+                    not_logged_in()
+                }
+            },
+            ["attachment", name] => match attachments.path(name) {
+                Some((path, mime)) => {
+                    let mime = mime.parse::<Mime>().unwrap();
+                    let forever = headers::CacheControl(vec![headers::CacheDirective::Public, headers::CacheDirective::MaxAge(31536000)]);
+                    Ok(Response::with((path, mime, status::Ok, Header(forever))))
+                },
+                None => render(&templates, "404", &user, "", 0, ""),
+            },
+            ["robots.txt"] => Ok(Response::with(("text/plain; charset=utf-8".parse::<Mime>().unwrap(), status::Ok, sitemap::robots()))),
+            ["sitemap.xml"] | ["sitemap", _] => { // An index, once there are too many posts for one file.
+                let xml = match req.url.path().get(1) {
+                    Some(file) => file.strip_suffix(".xml").map(|start| sitemap::page(&data, Some(start))).flatten(),
+                    None => sitemap::index(&data),
+                };
+                match xml {
+                    Some(xml) => Ok(Response::with(("application/xml; charset=utf-8".parse::<Mime>().unwrap(), status::Ok, xml))),
+                    None => render(&templates, "404", &user, "", 0, ""),
+                }
+            },
+            ["notifications"] | ["notifications", _] => {
+                let page = req.url.path().get(1).map(|p| p.parse::<u64>().ok()).flatten().unwrap_or(0);
+                data.login(&user)().map(|first_post_id| Post::read_notifications(&data, &first_post_id));
+                render(&templates, "notifications", &user, "", page, "")
+            },
+            ["feed"] | ["feed", _] => {
+                let page = req.url.path().get(1).map(|p| p.parse::<u64>().ok()).flatten().unwrap_or(0);
+                render(&templates, "feed", &user, "", page, "")
+            },
+            ["saved"] | ["saved", _] => {
+                let page = req.url.path().get(1).map(|p| p.parse::<u64>().ok()).flatten().unwrap_or(0);
+                render(&templates, "saved", &user, "", page, "")
+            },
+            ["tag", tag] | ["tag", tag, _] => { // ?sort=new for newest first
+                let page = req.url.path().get(2).map(|p| p.parse::<u64>().ok()).flatten().unwrap_or(0);
+                let sort = req.url.as_ref().query_pairs().find(|(k, _)| k == "sort").map(|(_, v)| v.into_owned()).unwrap_or_default();
+                match posts_api::normalize_tag(tag) {
+                    Some(tag) => {
+                        let body = templates.render("tag", &json!({
+                            "user": user,
+                            "url": format!("/tag/{}", tag),
+                            "tag": tag,
+                            "page": page,
+                            "sort": sort,
+                            "max_depth": 1,
+                        })).unwrap();
+                        Ok(Response::with((mime!(Text/Html), status::Ok, body)))
+                    },
+                    None => render(&templates, "404", &user, "", 0, ""),
+                }
+            },
             [template, post_id] if templates.has_template(template) => {
-                let post_id = data.lookup_url(post_id).unwrap_or_else(|| post_id.to_string());
-                render(&templates, &template, &user, &post_id, 0)
+                match data.resolve_url(post_id) {
+                    Some(Resolved::Moved(url)) => Ok(Response::with((status::MovedPermanently, RedirectRaw(format!("/{}/{}{}", template, url, key_query))))),
+                    Some(Resolved::Post(post_id)) => render(&templates, &template, &user, &post_id, 0, &key).map(|r| { if template == "post" && reading { Post::viewed(&data, &post_id, &user, &key) }; r }),
+                    None => render(&templates, &template, &user, post_id, 0, &key).map(|r| { if template == "post" && reading { Post::viewed(&data, post_id, &user, &key) }; r }),
+                }
             },
             [template, post_id, page] if templates.has_template(template) => {
-                let post_id = data.lookup_url(post_id).unwrap_or_else(|| post_id.to_string());
-                let page = page.parse::<u64>();
-                render(&templates, &template, &user, &post_id, page.unwrap_or(0))
+                let page = page.parse::<u64>().unwrap_or(0);
+                match data.resolve_url(post_id) {
+                    Some(Resolved::Moved(url)) => Ok(Response::with((status::MovedPermanently, RedirectRaw(format!("/{}/{}/{}{}", template, url, page, key_query))))),
+                    Some(Resolved::Post(post_id)) => render(&templates, &template, &user, &post_id, page, &key).map(|r| { if template == "post" && reading { Post::viewed(&data, &post_id, &user, &key) }; r }),
+                    None => render(&templates, &template, &user, post_id, page, &key).map(|r| { if template == "post" && reading { Post::viewed(&data, post_id, &user, &key) }; r }),
+                }
             },
             _ => match files.handle(req) {
                 Ok(x) => Ok(x),
                 Err(_) => {
-                    render(&templates, "404", &user, "", 0)
+                    render(&templates, "404", &user, "", 0, "")
                 }
             },
         }
@@ -257,5 +674,9 @@ That's all you need to know. Good luck.
     let mut args: Vec<String> = std::env::args().collect();
     let port = if args.len() >= 2 { args.swap_remove(1) } else { "1234".to_owned() };
     println!("Listening on port {}...", port);
-    Iron::new(chain).http(format!("0.0.0.0:{}", port)).unwrap();
+    let mut server = Iron::new(chain);
+    // Iron's default, unless `SERVER_THREADS` says otherwise. Event streams may only take up some of them.
+    server.threads = std::env::var("SERVER_THREADS").ok().map(|s| s.parse::<usize>().ok()).flatten().unwrap_or(server.threads);
+    data_for_streams.limit_listeners(server.threads);
+    server.http(format!("0.0.0.0:{}", port)).unwrap();
 }
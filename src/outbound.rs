@@ -0,0 +1,108 @@
+//! Guards requests to other servers (ActivityPub, Webmentions and webhooks), whose addresses come from users or from other servers.
+//!
+//! Those must not reach into the network that the site runs in: a host that resolves to a loopback, private, link-local (such as the cloud's metadata service at `169.254.169.254`) or otherwise non-public address is refused.
+//! The address that was checked is the one connected to, so that the name can't resolve to somewhere else by then. Redirects aren't followed here: whoever follows one must open its `Location` with `open` again.
+
+
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+
+use curl::easy::{Easy, List};
+use iron::url::{Host, Url};
+
+
+
+/// Makes a request to `url` (an `http` or `https` one), connecting only to the public address that its host resolves to, if it does.
+pub fn open(url: &str) -> Result<Easy, String> {
+    let e = |e: curl::Error| e.to_string();
+    let parsed = Url::parse(url).map_err(|e| e.to_string())?;
+    if parsed.scheme() != "https" && parsed.scheme() != "http" { return Err(format!("Not a web address: {}", url)) };
+    let port = parsed.port_or_known_default().unwrap_or(80);
+    let mut easy = Easy::new();
+    easy.url(url).map_err(e)?;
+    match parsed.host() {
+        Some(Host::Domain(domain)) => {
+            let addresses: Vec<SocketAddr> = (domain, port).to_socket_addrs().map_err(|e| format!("Could not find {}: {}", domain, e))?.collect();
+            if addresses.is_empty() || !addresses.iter().all(|a| allowed(a.ip())) { return Err(format!("Not a public address: {}", url)) };
+            let address = match addresses[0].ip() {
+                IpAddr::V4(ip) => ip.to_string(),
+                IpAddr::V6(ip) => format!("[{}]", ip),
+            };
+            let mut resolve = List::new();
+            resolve.append(&format!("{}:{}:{}", domain, port, address)).map_err(e)?;
+            easy.resolve(resolve).map_err(e)?;
+        },
+        Some(Host::Ipv4(ip)) if allowed(IpAddr::V4(ip)) => (),
+        Some(Host::Ipv6(ip)) if allowed(IpAddr::V6(ip)) => (),
+        _ => return Err(format!("Not a public address: {}", url)),
+    }
+    Ok(easy)
+}
+
+/// Whether requests may go to an address. (Tests talk to stand-ins on this machine.)
+fn allowed(ip: IpAddr) -> bool {
+    is_public(ip) || (cfg!(test) && ip.is_loopback())
+}
+
+/// Whether an address is one on the internet at large.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip),
+    }
+}
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified() || ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_broadcast() || ip.is_documentation() || ip.is_multicast()
+        || a == 0 // "This network".
+        || (a == 100 && (64..128).contains(&b)) // Carrier-grade NAT.
+        || (a == 192 && b == 0 && c == 0) // IETF protocol assignments.
+        || (a == 198 && (b == 18 || b == 19)) // Benchmarking.
+        || a >= 240) // Reserved.
+}
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let s = ip.segments();
+    let embedded = Ipv4Addr::new((s[6] >> 8) as u8, s[6] as u8, (s[7] >> 8) as u8, s[7] as u8);
+    if let Some(v4) = ip.to_ipv4_mapped() { return is_public_v4(v4) };
+    if s[..6] == [0x64, 0xff9b, 0, 0, 0, 0] { return is_public_v4(embedded) }; // NAT64.
+    !(ip.is_unspecified() || ip.is_loopback() || ip.is_multicast()
+        || s[..6] == [0; 6] // IPv4-compatible (deprecated).
+        || (s[0] & 0xfe00) == 0xfc00 // Unique local.
+        || (s[0] & 0xffc0) == 0xfe80 // Link-local.
+        || (s[0] & 0xffc0) == 0xfec0 // Site-local (deprecated).
+        || (s[0] == 0x2001 && s[1] == 0xdb8)) // Documentation.
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public(ip: &str) -> bool {
+        is_public(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn refuses_internal_addresses() {
+        for ip in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "0.0.0.0", "100.64.0.1", "255.255.255.255", "::1", "::", "fe80::1", "fd00::1", "::ffff:127.0.0.1", "::ffff:169.254.169.254", "64:ff9b::a00:1"] {
+            assert!(!public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn allows_public_addresses() {
+        for ip in ["93.184.216.34", "8.8.8.8", "172.32.0.1", "2606:4700::1111", "::ffff:8.8.8.8"] {
+            assert!(public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn opens_only_public_hosts() {
+        assert!(open("http://169.254.169.254/latest/meta-data/").is_err());
+        assert!(open("http://10.0.0.1:8080/").is_err());
+        assert!(open("http://[fd00::1]/").is_err());
+        assert!(open("ftp://example.com/").is_err());
+        assert!(open("http://127.0.0.1:1/").is_ok()); // Only in tests.
+    }
+}
\ No newline at end of file
@@ -1,9 +1,10 @@
 use std::thread::JoinHandle;
 use std::sync::Mutex;
 use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
 
 mod hashing;
-pub use hashing::access_token_hash;
+pub use hashing::{access_token_hash, content_hash, signature};
 use crate::posts_store::Database;
 
 use uuid::Uuid;
@@ -21,6 +22,48 @@ pub fn new_uuid() -> String {
 
 
 
+/// Whether a user may move anyone's posts: their access hash is in `MODERATORS` (comma-separated).
+pub fn is_moderator(user: &str) -> bool {
+    let access_hash = access_token_hash(user);
+    std::env::var("MODERATORS").unwrap_or_default().split(',').any(|m| m.trim() == access_hash)
+}
+
+/// The site's address, from the `SITE_URL` environment variable (such as `https://example.com`), without a trailing `/`.
+pub fn site_url() -> String {
+    std::env::var("SITE_URL").unwrap_or_else(|_| "https://text-sharing-site.herokuapp.com".to_owned()).trim_end_matches('/').to_owned()
+}
+
+
+
+/// A post can have at most this many tags.
+pub const MAX_TAGS: usize = 10;
+
+/// What's shown of a post that burns after reading, anywhere but on its own page (where opening it counts).
+pub const UNREAD_PLACEHOLDER: &str = "*This post disappears after it's first opened.*";
+
+/// What's left of an erased post that others had replied to.
+const TOMBSTONE: &str = "*This post was deleted.*";
+
+/// The most bytes of content that a new post may have.
+pub const MAX_CONTENT: usize = 50000;
+
+/// How deep under the root a post can be moved to (each level being one read, to make sure it's not under itself).
+const MAX_DEPTH: usize = 100;
+
+/// Turns what a user typed into a tag, such as `"#Rust"` into `"rust"`, if it can be one: up to 32 ASCII letters, digits, `_` and `-`.
+///
+/// ```
+/// assert_eq!(normalize_tag("#Rust"), Some("rust".to_owned()));
+/// assert_eq!(normalize_tag("two words"), None);
+/// ```
+pub fn normalize_tag(tag: &str) -> Option<String> {
+    let tag = tag.trim().trim_start_matches('#').to_ascii_lowercase();
+    if tag.is_empty() || tag.len() > 32 || !tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') { return None };
+    Some(tag)
+}
+
+
+
 /// Who can create sub-posts in the parent post.
 #[derive(Clone)]
 pub enum CanPost {
@@ -31,8 +74,65 @@ pub enum CanPost {
 
 
 
+/// Who can find and read a post, from the most open to the most restricted. A reply is at most as open as its parent.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    /// Listed under its parent.
+    #[default]
+    Public,
+    /// Not listed anywhere, but readable by anyone with its URL.
+    Unlisted,
+    /// Readable only through its share link, which has a secret key.
+    Private,
+}
+
+
+
+/// How a new post is made, apart from its content.
+pub struct Settings {
+    pub children_rights: CanPost,
+    pub publishing: Publishing,
+    pub expiry: Expiry,
+    pub visibility: Visibility,
+}
+
+
+
+/// When a new post becomes visible to others.
+pub enum Publishing {
+    Now,
+    /// Only when its author publishes it.
+    Draft,
+    /// At this Unix timestamp, or when its author publishes it, whichever is first.
+    At(i64),
+}
+
+
+
+/// When a new post is erased by itself.
+pub enum Expiry {
+    Never,
+    /// This many seconds after it's written.
+    After(i64),
+    /// After the first time that someone other than its author opens it.
+    FirstView,
+}
+
+
+
+/// What happens to the posts of a deleted account.
+pub enum Leaving {
+    /// Posts are removed. (Posts that others have replied to are replaced by a tombstone, to keep the replies in place.)
+    Erase,
+    /// Posts stay, but are no longer anyone's.
+    Anonymize,
+}
+
+
+
 /// Returns how many seconds have passed since the Unix Epoch (1970-01-01 00:00:00 UTC).
-fn timestamp() -> i64 {
+pub fn timestamp() -> i64 {
     std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as i64
 }
 
@@ -64,6 +164,16 @@ where F: FnMut(i64) -> i64 {
 
 
 
+/// Reads `listed_post_ids/<access_hash>` (as JSON, if read) into (when created, negated; post ID), newest first.
+fn listed_entries(body: Option<String>) -> Vec<(i64, String)> {
+    let entries = body.map(|b| from_str::<HashMap<String, i64>>(&b).ok()).flatten().unwrap_or_default();
+    let mut entries: Vec<(i64, String)> = entries.into_iter().map(|(id, date)| (date, id)).collect();
+    entries.sort();
+    entries
+}
+
+
+
 /// A post: a published piece of information, communicated through Markdown.
 /// 
 /// For example, a user is just another kind of post (with a new access_hash).
@@ -79,12 +189,28 @@ pub struct Post {
     //   (Gates write access: creating posts, editing and 'deleting' them, and rewarding any posts.)
     //     (Password is copied into posts, so can't change it.)
     pub human_readable_url: String, // A human-readable name, such as "2020_first_line".
+    #[serde(default)]
+    pub custom_url: bool, // Whether the author chose `human_readable_url`, so it's never re-made from the content.
     pub content: String, // Intended to be Markdown, with the first line displayed as the title.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>, // Normalized (see `normalize_tag`), and at most `MAX_TAGS`.
     reward: i64,
     parent_id: String,
     children_rights: CanPost,
     gave_reward: i8,
     reverse_date_created: i64,
+    #[serde(default)]
+    draft: bool, // Drafts are seen only by their authors, and aren't listed under their parents.
+    #[serde(default)]
+    publish_at: i64, // When a draft is scheduled to be published (0 if it isn't).
+    #[serde(default)]
+    expires_at: i64, // When the post stops being readable, and is due to be erased (0 if never).
+    #[serde(default)]
+    burn_after_reading: bool, // Whether the post expires once it's first viewed.
+    #[serde(default)]
+    visibility: Visibility,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    share_key: String, // For private posts, what their share link must contain.
 }
 
 impl Post {
@@ -96,50 +222,93 @@ impl Post {
             id: id.clone(),
             access_hash: "".to_string(), // No user can edit it (except for our own functions).
             human_readable_url: "".to_string(),
+            custom_url: false,
             content,
+            tags: vec![],
             reward: 0i64,
             parent_id: id,
             children_rights: CanPost::All,
             gave_reward: 0i8,
             reverse_date_created: -timestamp(),
+            draft: false,
+            publish_at: 0,
+            expires_at: 0,
+            burn_after_reading: false,
+            visibility: Visibility::Public,
+            share_key: "".to_string(),
         }
     }
     /// Adds a new child-post to a parent-post.
-    /// Also pushes to a user's created posts (no way to atomize this with firebase_rs).
+    /// Also pushes to a user's created posts (no way to atomize this with firebase_rs), and notifies those subscribed to the thread.
+    /// Drafts are only pushed to the user's drafts (and to the schedule), and get added to the parent when published. Unlisted and private posts never are.
+    /// Posts that expire after some time are pushed to the list that `sweep_expired` goes through.
     /// `access_hash` must be `crate::posts_api::access_token_hash(user)`.
     /// Returns (parent, Option<child>).
-    pub fn new(data: &Database, parent: Post, access_hash: &str, content: String, children_rights: CanPost) -> (Post, Option<Post>) {
-        if content.len() > 50000 {
+    pub fn new(data: &Database, parent: Post, access_hash: &str, content: String, settings: Settings) -> (Post, Option<Post>) {
+        let Settings{ children_rights, publishing, expiry, visibility } = settings;
+        let visibility = visibility.max(parent.visibility.clone());
+        if content.len() > MAX_CONTENT {
             return (parent, None)
         }
-        let rights = &parent.children_rights;
-        if matches!(rights, CanPost::All) || matches!(rights, CanPost::Itself) && &parent.access_hash == access_hash {
+        if parent.accepts_child(access_hash) {
             let id = new_uuid();
             let mut handles: Vec<JoinHandle<()>> = vec![];
             data.at(&["created_post_ids", &access_hash.replace(|c:char| !c.is_ascii_alphanumeric(), "_")]).ok().map(|node| {
                 handles.push(node.push_async(&format!("{{\"post_id\":\"{}\"}}", id), |_| ()))
             });
-            data.at(&["children", &parent.id]).ok().map(|node| {
-                let b = Some(format!("{{\"{}\":0}}", id));
-                b.map(|body| handles.push(node.update_async(body, |_| ())));
-            });
-            atomic_update(data, &["children_length", &parent.id], 0i64, |v| v+1);
+            let publish_at = match publishing {
+                Publishing::Now if visibility != Visibility::Public => None,
+                Publishing::Now => {
+                    data.at(&["children", &parent.id]).ok().map(|node| {
+                        let b = Some(format!("{{\"{}\":0}}", id));
+                        b.map(|body| handles.push(node.update_async(body, |_| ())));
+                    });
+                    atomic_update(data, &["children_length", &parent.id], 0i64, |v| v+1);
+                    None
+                },
+                Publishing::Draft => Some(0),
+                Publishing::At(t) => {
+                    // Publishing when due needs a `".indexOn": ".value"` rule on `"scheduled"`.
+                    data.at(&["scheduled", &id]).ok().map(|node| handles.push(node.set_async(t.to_string(), |_| ())));
+                    Some(t)
+                },
+            };
+            if let Some(t) = publish_at {
+                data.at(&["drafts", &access_hash.replace(|c:char| !c.is_ascii_alphanumeric(), "_"), &id]).ok().map(|node| {
+                    handles.push(node.set_async(t.to_string(), |_| ()))
+                });
+            }
+            let expires_at = match expiry {
+                Expiry::After(seconds) => timestamp() + seconds,
+                _ => 0,
+            };
+            if expires_at != 0 {
+                // Sweeping needs a `".indexOn": ".value"` rule on `"expiring"`.
+                data.at(&["expiring", &id]).ok().map(|node| handles.push(node.set_async(expires_at.to_string(), |_| ())));
+            }
             for handle in handles { handle.join().unwrap(); }
             let parent_id = parent.id.clone();
-            (
-                parent,
-                Some(Post {
-                    id,
-                    access_hash: access_hash.to_string(),
-                    human_readable_url: "".to_string(),
-                    content,
-                    reward: 0i64,
-                    parent_id,
-                    children_rights,
-                    gave_reward: 0i8,
-                    reverse_date_created: -timestamp(),
-                })
-            )
+            let child = Post {
+                id,
+                access_hash: access_hash.to_string(),
+                human_readable_url: "".to_string(),
+                custom_url: false,
+                content,
+                tags: vec![],
+                reward: 0i64,
+                parent_id,
+                children_rights,
+                gave_reward: 0i8,
+                reverse_date_created: -timestamp(),
+                draft: publish_at.is_some(),
+                publish_at: publish_at.unwrap_or(0),
+                expires_at,
+                burn_after_reading: matches!(expiry, Expiry::FirstView),
+                share_key: if visibility == Visibility::Private { new_uuid() } else { "".to_string() },
+                visibility,
+            };
+            if child.listed() { child.notify_subscribers(data) };
+            (parent, Some(child))
         } else {
             (parent, None)
         }
@@ -161,7 +330,7 @@ impl Post {
     /// Returns (user_first_post, Option<rewarded_post>), and has some side-effects.
     /// Disgustingly non-atomic.
     pub fn reward(self: Post, data: &Database, mut user_first_post: Post, amount: i8) -> (Post, Option<Post>) {
-        if amount != -100 && amount != -1 && amount != 0 && amount != 1 {
+        if self.draft || amount != -100 && amount != -1 && amount != 0 && amount != 1 {
             return (user_first_post, None)
         };
         if amount == -100 && (self.access_hash != user_first_post.access_hash || self.access_hash == "") {
@@ -179,11 +348,13 @@ impl Post {
         data.at(&at).ok().map(|n| to_string(&amount).ok().map(|s| n.set(&s).ok())).flatten().flatten();
         let delta = amount - old;
         let reward = self.reward + (delta as i64);
-        data.at(&["children", &self.parent_id]).ok().map(|node| {
-            // Update the reward in the child-list.
-            let b = Some(format!("{{\"{}\":{}}}", self.id, -reward));
-            b.map(|body| node.update(&body));
-        });
+        if self.listed() {
+            data.at(&["children", &self.parent_id]).ok().map(|node| {
+                // Update the reward in the child-list.
+                let b = Some(format!("{{\"{}\":{}}}", self.id, -reward));
+                b.map(|body| node.update(&body));
+            });
+        }
         if self.id != user_first_post.id {
             (user_first_post, Some(Post{
                 reward,
@@ -196,28 +367,590 @@ impl Post {
             }, None)
         }
     }
+    /// Deletes a user's account: takes back the rewards they gave, erases or anonymizes their posts, and forgets their saved posts, follows and access token.
+    /// (Rewards that others gave to erased posts, and the user's subscriptions, are left for the `check --fix` command to clean up, since finding them means reading all rewards and subscribers.)
+    /// Returns whether `user` was an account.
+    pub fn delete_account(data: &Database, user: &str, leaving: Leaving) -> bool {
+        let first_post_id = match data.login(user)() {
+            Some(id) => id,
+            None => return false,
+        };
+        let access_hash = access_token_hash(user);
+        let at = ["user_reward", &first_post_id];
+        let given = data.at(&at).ok().map(|n| n.get().ok()).flatten().map(|r| from_str::<HashMap<String, i8>>(&r.body).ok()).flatten().unwrap_or_default();
+        for (id, amount) in given {
+            let id = if id == "_" { "" } else { &id };
+            data.update(vec![id], |mut posts| match posts.remove(0) {
+                Some(post) => vec![Some(post.rerank(data, -(amount as i64)))],
+                None => vec![],
+            });
+        }
+        data.at(&at).ok().map(|n| n.delete("").ok());
+
+        #[derive(Deserialize)]
+        struct Created { post_id: String }
+        let at = ["created_post_ids", &access_hash.replace(|c:char| !c.is_ascii_alphanumeric(), "_")];
+        let created = data.at(&at).ok().map(|n| n.get().ok()).flatten().map(|r| from_str::<HashMap<String, Created>>(&r.body).ok()).flatten().unwrap_or_default();
+        let ids: Vec<String> = created.into_values().map(|c| c.post_id).collect();
+        let mut posts: Vec<Post> = ids.chunks(32).flat_map(|ids| data.read(ids.iter().map(|s| &s[..]).collect())).flatten().collect();
+        // Newest first, so that a user's replies to their own posts are gone before those posts are looked at.
+        posts.sort_by_key(|p| p.reverse_date_created);
+        for post in posts {
+            let id = post.id.clone();
+            match leaving {
+                _ if post.draft => post.remove(data), // No one else has seen it.
+                Leaving::Erase => post.erase(data),
+                Leaving::Anonymize => data.update(vec![&id], |_| vec![Some(Post{
+                    access_hash: "".to_string(),
+                    ..post
+                })]),
+            }
+        }
+        data.at(&at).ok().map(|n| n.delete("").ok());
+        data.at(&["saved", &first_post_id]).ok().map(|n| n.delete("").ok());
+        data.at(&["following", &first_post_id]).ok().map(|n| n.delete("").ok());
+        data.at(&["notifications", &first_post_id]).ok().map(|n| n.delete("").ok());
+        data.at(&["unread_notifications", &first_post_id]).ok().map(|n| n.delete("").ok());
+        data.at(&["ap_followers", &access_hash]).ok().map(|n| n.delete("").ok());
+        data.at(&["access_hash", &access_hash]).ok().map(|n| n.delete("").ok());
+        true
+    }
+    /// Changes a post's reward by `delta`, moving it in its parent's child-list.
+    fn rerank(self: Post, data: &Database, delta: i64) -> Post {
+        let reward = self.reward + delta;
+        if self.listed() {
+            data.at(&["children", &self.parent_id]).ok().map(|node| {
+                let b = Some(format!("{{\"{}\":{}}}", self.id, -reward));
+                b.map(|body| node.update(&body));
+            });
+        }
+        Post{
+            reward,
+            ..self
+        }
+    }
+    /// Removes a post, or, if others have replied to it, replaces it with a tombstone that keeps the replies in place.
+    /// The tombstone is at `/post/<id>`, since URLs made from the content would still tell what it was.
+    fn erase(self: Post, data: &Database) {
+        let replied = data.at(&["children_length", &self.id]).ok().map(|n| n.get().ok()).flatten().map(|r| from_str::<i64>(&r.body).ok()).flatten().unwrap_or(0) > 0;
+        if !replied { return self.remove(data) };
+        data.at(&["expiring", &self.id]).ok().map(|n| n.delete("").ok());
+        let id = self.id.clone();
+        data.update(vec![&id], |_| vec![Some(Post{
+            access_hash: "".to_string(),
+            human_readable_url: id.clone(),
+            custom_url: true,
+            content: TOMBSTONE.to_string(),
+            tags: vec![],
+            expires_at: 0,
+            burn_after_reading: false,
+            ..self
+        })]);
+        data.forget_urls(&id, &id);
+    }
+    /// Removes a post, and every mention of it, from the database. It must have no children.
+    fn remove(self: Post, data: &Database) {
+        Post::index_tags(data, Some(&self), &Post{ tags: vec![], ..self.clone() });
+        if self.draft {
+            data.at(&["drafts", &self.access_hash.replace(|c:char| !c.is_ascii_alphanumeric(), "_"), &self.id]).ok().map(|n| n.delete("").ok());
+            data.at(&["scheduled", &self.id]).ok().map(|n| n.delete("").ok());
+        }
+        if self.listed() {
+            data.at(&["children", &self.parent_id, &self.id]).ok().map(|n| n.delete("").ok());
+            atomic_update(data, &["children_length", &self.parent_id], 0i64, |v| v-1);
+        }
+        data.forget_urls(&self.id, "");
+        data.at(&["children_length", &self.id]).ok().map(|n| n.delete("").ok());
+        data.at(&["expiring", &self.id]).ok().map(|n| n.delete("").ok());
+        crate::webhooks::remove_all(data, &self.id);
+        data.at(&["webmentions", &self.id]).ok().map(|n| n.delete("").ok());
+        data.at(&["sitemap", &self.id]).ok().map(|n| n.delete("").ok());
+        data.at(&["listed_post_ids", &self.access_hash.replace(|c:char| !c.is_ascii_alphanumeric(), "_"), &self.id]).ok().map(|n| n.delete("").ok());
+        data.at(&["posts", &self.id]).ok().map(|n| n.delete("").ok());
+    }
+    /// Moves a post, with all its replies, under another parent, if `user` is its author (or a moderator) and may post there.
+    /// It becomes at most as open as its new parent, unless that would hide its listed replies, in which case it isn't moved. If the post was listed, a notice that links to it is left in its old place.
+    /// Returns (Option<moved post>, Option<notice>).
+    pub fn move_to(self: Post, data: &Database, new_parent: &Post, user: &str) -> (Option<Post>, Option<Post>) {
+        let token = access_token_hash(user);
+        let moderator = is_moderator(user);
+        if !moderator && (self.access_hash != token || self.access_hash == "") { return (None, None) };
+        let rights = &new_parent.children_rights;
+        let allowed = moderator || matches!(rights, CanPost::All) || matches!(rights, CanPost::Itself) && new_parent.access_hash == token;
+        if !allowed || new_parent.draft || self.id == self.parent_id || new_parent.id == self.parent_id { return (None, None) };
+        // A post can't go under itself or its own replies.
+        let mut ancestor = Some(new_parent.clone());
+        let mut depth = 0;
+        while let Some(a) = ancestor {
+            if a.id == self.id || depth == MAX_DEPTH { return (None, None) };
+            if a.id == a.parent_id { break };
+            ancestor = data.read(vec![&a.parent_id]).pop().flatten();
+            depth += 1;
+        }
+        let visibility = self.visibility.clone().max(new_parent.visibility.clone());
+        if visibility != self.visibility {
+            // Its listed replies would have to follow, and they may have their own.
+            let replied = data.at(&["children_length", &self.id]).ok().map(|n| n.get().ok()).flatten().map(|r| from_str::<i64>(&r.body).ok()).flatten().unwrap_or(0) > 0;
+            if replied { return (None, None) };
+        }
+        let moved = Post{
+            parent_id: new_parent.id.clone(),
+            share_key: if visibility == Visibility::Private && self.share_key == "" { new_uuid() } else { self.share_key.clone() },
+            visibility,
+            ..self.clone()
+        };
+        let notice = if self.listed() {
+            let url = "/post/".to_owned() + if self.human_readable_url == "" { &self.id } else { &self.human_readable_url };
+            let notice = Post{
+                parent_id: self.parent_id.clone(),
+                children_rights: CanPost::None,
+                ..Post::new_public(None, format!("*This post was moved [elsewhere]({}).*", url))
+            };
+            let mut handles: Vec<JoinHandle<()>> = vec![];
+            data.at(&["children", &self.parent_id]).ok().map(|node| {
+                // The notice takes the post's place, so the old parent's `children_length` stays the same.
+                let b = Some(format!("{{\"{}\":null,\"{}\":0}}", self.id, notice.id));
+                b.map(|body| handles.push(node.update_async(body, |_| ())));
+            });
+            if moved.listed() {
+                data.at(&["children", &new_parent.id]).ok().map(|node| {
+                    let b = Some(format!("{{\"{}\":{}}}", self.id, -self.reward));
+                    b.map(|body| handles.push(node.update_async(body, |_| ())));
+                });
+                atomic_update(data, &["children_length", &new_parent.id], 0i64, |v| v+1);
+            }
+            for handle in handles { handle.join().unwrap(); }
+            Some(notice)
+        } else {
+            None
+        };
+        (Some(moved), notice)
+    }
+    /// Publishes a draft: lists it under its parent (if public), and dates it to now.
+    pub fn publish(self: Post, data: &Database) -> Post {
+        if !self.draft { return self };
+        let mut handles: Vec<JoinHandle<()>> = vec![];
+        if self.visibility == Visibility::Public {
+            data.at(&["children", &self.parent_id]).ok().map(|node| {
+                let b = Some(format!("{{\"{}\":{}}}", self.id, -self.reward));
+                b.map(|body| handles.push(node.update_async(body, |_| ())));
+            });
+            atomic_update(data, &["children_length", &self.parent_id], 0i64, |v| v+1);
+        }
+        data.at(&["drafts", &self.access_hash.replace(|c:char| !c.is_ascii_alphanumeric(), "_"), &self.id]).ok().map(|node| {
+            handles.push(node.delete_async("", |_| ()))
+        });
+        data.at(&["scheduled", &self.id]).ok().map(|node| handles.push(node.delete_async("", |_| ())));
+        for handle in handles { handle.join().unwrap(); }
+        let post = Post{
+            draft: false,
+            publish_at: 0,
+            reverse_date_created: -timestamp(),
+            ..self
+        };
+        if post.listed() { post.notify_subscribers(data) };
+        post
+    }
+    /// Publishes every scheduled draft whose time has come.
+    pub fn publish_due(data: &Database) {
+        let response = data.at(&["scheduled"]).ok().map(|n| {
+            let mut n = n.with_params();
+            let url = Arc::get_mut(&mut n.url).unwrap();
+            url.set_query(Some(&format!("orderBy={}&endAt={}", "\"$value\"", timestamp())));
+            n.get().ok()
+        }).flatten();
+        let due = response.map(|r| from_str::<HashMap<String, i64>>(&r.body).ok()).flatten().unwrap_or_default();
+        for id in due.keys() {
+            data.update(vec![id], |mut posts| match posts.remove(0) {
+                Some(post) => vec![Some(post.publish(data))],
+                None => {
+                    data.at(&["scheduled", id]).ok().map(|n| n.delete("").ok());
+                    vec![]
+                },
+            });
+        }
+    }
+    /// Whether a post can no longer be read (and is waiting to be erased).
+    pub fn is_expired(self: &Post) -> bool {
+        self.expires_at != 0 && self.expires_at <= timestamp()
+    }
+    /// Whether a post was erased, and is only kept for its replies.
+    fn is_tombstone(&self) -> bool {
+        self.access_hash == "" && self.content == TOMBSTONE
+    }
+    /// Whether a post belongs in the sitemap (see `index_sitemap`).
+    fn in_sitemap(&self) -> bool {
+        self.listed() && self.expires_at == 0 && !self.burn_after_reading && !self.is_tombstone()
+    }
+    /// Counts a view of a post's page by a user, given the key from the link that they followed: a post that burns after reading expires once someone other than its author has seen it.
+    pub fn viewed(data: &Database, id: &str, user: &str, key: &str) {
+        data.update(vec![id], |mut posts| match posts.remove(0) {
+            Some(post) if post.burns_for(user, key) => {
+                let expires_at = timestamp();
+                data.at(&["expiring", &post.id]).ok().map(|n| n.set(&expires_at.to_string()).ok());
+                vec![Some(Post{
+                    expires_at,
+                    ..post
+                })]
+            },
+            _ => vec![],
+        });
+    }
+    /// Whether a user's view burns this post: only if they could see it (so not if it's a draft, or private and they lack the key), and aren't its author.
+    fn burns_for(self: &Post, user: &str, key: &str) -> bool {
+        self.burn_after_reading && self.expires_at == 0 && !self.draft && self.visible_to(user, key) && access_token_hash(user) != self.access_hash
+    }
+    /// Erases every post that has expired, along with its place among its parent's children and its URL.
+    pub fn sweep_expired(data: &Database) {
+        let response = data.at(&["expiring"]).ok().map(|n| {
+            let mut n = n.with_params();
+            let url = Arc::get_mut(&mut n.url).unwrap();
+            url.set_query(Some(&format!("orderBy={}&endAt={}", "\"$value\"", timestamp())));
+            n.get().ok()
+        }).flatten();
+        let due = response.map(|r| from_str::<HashMap<String, i64>>(&r.body).ok()).flatten().unwrap_or_default();
+        for id in due.keys() {
+            match data.read_including_expired(vec![id]).pop().flatten() {
+                Some(post) => post.erase(data),
+                None => { data.at(&["expiring", id]).ok().map(|n| n.delete("").ok()); },
+            }
+        }
+    }
+    /// Gets the IDs of a user's drafts (both unscheduled and scheduled).
+    pub fn get_drafts(data: &Database, access_hash: &str) -> Vec<String> {
+        let node = data.at(&["drafts", &access_hash.replace(|c:char| !c.is_ascii_alphanumeric(), "_")]).ok();
+        let r = node.map(|n| n.with_params().shallow(true).get().ok()).flatten();
+        r.map(|r| from_str::<HashMap<String, bool>>(&r.body).ok()).flatten().map(|m| m.into_keys().collect()).unwrap_or_default()
+    }
+    /// Whether a user (by `access_hash`) can reply to this post.
+    pub fn accepts_child(self: &Post, access_hash: &str) -> bool {
+        let rights = &self.children_rights;
+        !self.draft && (matches!(rights, CanPost::All) || matches!(rights, CanPost::Itself) && self.access_hash == access_hash)
+    }
+    /// Whether a user can see this post, given the key from the link that they followed (`""` if none).
+    /// Drafts are only for their authors, and private posts also for those with the share link.
+    pub fn visible_to(self: &Post, user: &str, key: &str) -> bool {
+        if access_token_hash(user) == self.access_hash { return true };
+        !self.draft && (self.visibility != Visibility::Private || key == self.share_key)
+    }
+    /// Keeps the tag index in step with a post that's being written (`old` is how it was, if it existed).
+    /// Only listed posts are in the index, under `tags/<tag>/<post_id>` as `{ reward, date }` (negated, to sort by descending reward and newest first), with `tag_counts/<tag>` counting them.
+    pub fn index_tags(data: &Database, old: Option<&Post>, new: &Post) {
+        let old_tags: &[String] = match old { Some(p) if p.listed() => &p.tags, _ => &[] };
+        let new_tags: &[String] = if new.listed() { &new.tags } else { &[] };
+        let moved = old.map(|p| p.reward != new.reward || p.reverse_date_created != new.reverse_date_created).unwrap_or(true);
+        let entry = json!({ "reward": -new.reward, "date": new.reverse_date_created }).to_string();
+        let mut handles: Vec<JoinHandle<()>> = vec![];
+        for tag in old_tags.iter().filter(|t| !new_tags.contains(t)) {
+            data.at(&["tags", tag, &new.id]).ok().map(|node| handles.push(node.delete_async("", |_| ())));
+            atomic_update(data, &["tag_counts", tag], 0i64, |v| v-1);
+        }
+        for tag in new_tags {
+            let added = !old_tags.contains(tag);
+            if added || moved {
+                // Tag pages need a `".indexOn": ["reward", "date"]` rule on `"tags"/"$tag"`.
+                data.at(&["tags", tag, &new.id]).ok().map(|node| handles.push(node.set_async(entry.clone(), |_| ())));
+            }
+            if added { atomic_update(data, &["tag_counts", tag], 0i64, |v| v+1) };
+        }
+        for handle in handles { handle.join().unwrap(); }
+    }
+    /// Keeps the sitemap in step with a post that's being written (`old` is how it was, if it existed).
+    /// Listed posts that aren't due to expire (nor tombstones) are under `sitemap/<post_id>` as `{ url, modified }`, the latter being when their content or URL last changed.
+    pub fn index_sitemap(data: &Database, old: Option<&Post>, new: &Post) {
+        let mapped = Post::in_sitemap;
+        let changed = match old {
+            Some(p) if mapped(p) => p.content != new.content || p.human_readable_url != new.human_readable_url,
+            _ => true,
+        };
+        if mapped(new) {
+            if !changed { return };
+            let entry = json!({ "url": format!("/post/{}", new.human_readable_url), "modified": timestamp() });
+            data.at(&["sitemap", &new.id]).ok().map(|n| n.set(&entry.to_string()).ok());
+        } else if old.map(mapped).unwrap_or(false) {
+            data.at(&["sitemap", &new.id]).ok().map(|n| n.delete("").ok());
+        }
+    }
+    /// Keeps the index of each user's listed posts in step with a post that's being written (`old` is how it was, if it existed).
+    /// They're under `listed_post_ids/<access_hash>/<post_id>` as when they were created (negated, to list the newest first), leaving out posts that burn after reading. Feeds are made of these, so they show (and count) nothing hidden.
+    pub fn index_author(data: &Database, old: Option<&Post>, new: &Post) {
+        let indexed = |p: &Post| p.listed() && !p.burn_after_reading && p.access_hash != "";
+        let node = |p: &Post| data.at(&["listed_post_ids", &p.access_hash.replace(|c:char| !c.is_ascii_alphanumeric(), "_"), &p.id]).ok();
+        let was = old.filter(|p| indexed(p));
+        if let Some(old) = was {
+            if !indexed(new) || old.access_hash != new.access_hash {
+                node(old).map(|n| n.delete("").ok());
+            }
+        }
+        if indexed(new) && was.map(|p| p.access_hash != new.access_hash).unwrap_or(true) {
+            node(new).map(|n| n.set(&new.reverse_date_created.to_string()).ok());
+        }
+    }
+    /// Queries a user's newest `end` listed posts.
+    fn listed_by(data: &Database, access_hash: &str, end: usize) -> Option<firebase_rs::FirebaseParams> {
+        data.at(&["listed_post_ids", &access_hash.replace(|c:char| !c.is_ascii_alphanumeric(), "_")]).ok().map(|n| {
+            // Needs a `".indexOn": ".value"` rule on `"listed_post_ids"/"$hash"`.
+            let mut n = n.with_params();
+            let url = Arc::get_mut(&mut n.url).unwrap();
+            url.set_query(Some(&format!("orderBy={}&limitToFirst={}", "\"$value\"", end)));
+            n
+        })
+    }
+    /// Gets the IDs of a user's listed posts, newest first, from the `start`th to before the `end`th.
+    pub fn get_listed_by(data: &Database, access_hash: &str, start: usize, end: usize) -> Vec<String> {
+        let r = Post::listed_by(data, access_hash, end).map(|n| n.get().ok()).flatten();
+        listed_entries(r.map(|r| r.body)).into_iter().skip(start).map(|(_, id)| id).collect()
+    }
+    /// Gets how many listed posts a user has.
+    pub fn get_listed_count(data: &Database, access_hash: &str) -> usize {
+        let r = data.at(&["listed_post_ids", &access_hash.replace(|c:char| !c.is_ascii_alphanumeric(), "_")]).ok().map(|n| n.with_params().shallow(true).get().ok()).flatten();
+        r.map(|r| from_str::<HashMap<String, bool>>(&r.body).ok()).flatten().map(|m| m.len()).unwrap_or(0)
+    }
+    /// Tells live listeners and webhooks about a post that's being written (`old` is how it was, if it existed).
+    /// A post's own stream and webhooks hear of its `edit`s, `reward` changes and `move`s; its parent's, of it being added (`child`, once it's listed). Its parent's stream also hears of its `reward` changes (which reorder the children), and both its old and new parents' streams of it moving.
+    /// Once listed, it's also sent to its author's followers on other servers (again, as an update, when it moves), and the pages it links to are sent Webmentions (again when its content changes, also to those it no longer links to).
+    /// Posts that burn after reading are only announced with `UNREAD_PLACEHOLDER` as their content, and aren't sent elsewhere (where reading them wouldn't count).
+    pub fn announce(data: &Database, old: Option<&Post>, new: &Post) {
+        let content = if new.burn_after_reading { UNREAD_PLACEHOLDER } else { &new.content };
+        let added = new.listed() && new.id != new.parent_id && old.map(|p| !p.listed()).unwrap_or(true);
+        if added {
+            let event = json!({ "post_id": new.id, "parent_id": new.parent_id, "content": content });
+            data.emit(&new.parent_id, "child", &event);
+            crate::webhooks::trigger(data, &new.parent_id, "child", event, None);
+            crate::activitypub::publish(data, new);
+        }
+        if let Some(old) = old.filter(|p| p.listed() && new.listed() && p.parent_id != new.parent_id) {
+            let event = json!({ "post_id": new.id, "parent_id": new.parent_id, "old_parent_id": old.parent_id });
+            data.emit(&new.id, "move", &event);
+            data.emit(&old.parent_id, "move", &event);
+            data.emit(&new.parent_id, "move", &event);
+            crate::webhooks::trigger(data, &new.id, "move", event, None);
+            crate::activitypub::republish(data, new);
+        }
+        if new.listed() && !new.burn_after_reading && old.map(|p| !p.listed() || p.content != new.content).unwrap_or(true) {
+            let mut links = crate::posts_helpers::links(&new.content);
+            if let Some(old) = old {
+                let gone: Vec<String> = crate::posts_helpers::links(&old.content).into_iter().filter(|l| !links.contains(l)).collect();
+                links.extend(gone);
+            }
+            crate::webmention::send(&format!("/post/{}", new.human_readable_url), links);
+        }
+        if let Some(old) = old {
+            if old.content != new.content {
+                let event = json!({ "post_id": new.id, "content": content });
+                data.emit(&new.id, "edit", &event);
+                crate::webhooks::trigger(data, &new.id, "edit", event, None);
+            }
+            if old.reward != new.reward {
+                let event = json!({ "post_id": new.id, "reward": new.reward });
+                data.emit(&new.id, "reward", &event);
+                if new.listed() { data.emit(&new.parent_id, "reward", &event) };
+                crate::webhooks::trigger(data, &new.id, "reward", event, None);
+            }
+        }
+    }
+    /// Gets the specified IDs of posts with a tag, most-reward (or, `by_date`, newest) first.
+    pub fn get_tagged(tag: &str, data: &Database, start: usize, end: usize, by_date: bool) -> Vec<String> {
+        let order = if by_date { "date" } else { "reward" };
+        let response = data.at(&["tags", tag]).ok().map(|n| {
+            let mut n = n.with_params();
+            let url = Arc::get_mut(&mut n.url).unwrap();
+            // Like children, filtered client-side.
+            url.set_query(Some(&format!("orderBy=\"{}\"&limitToFirst={}", order, end)));
+            n.get().ok()
+        }).flatten();
+        let entries = response.map(|r| from_str::<HashMap<String, HashMap<String, i64>>>(&r.body).ok()).flatten().unwrap_or_default();
+        let mut ids: Vec<String> = entries.keys().cloned().collect();
+        ids.sort_by_key(|id| entries[id].get(order).copied().unwrap_or(0));
+        ids.into_iter().skip(start).take(end.saturating_sub(start)).collect()
+    }
+    /// Gets how many posts have a tag.
+    pub fn get_tag_count(data: &Database, tag: &str) -> i64 {
+        data.at(&["tag_counts", tag]).ok().map(|n| n.get().ok()).flatten().map(|r| from_str::<i64>(&r.body).ok()).flatten().unwrap_or(0)
+    }
+    /// Gets the most-used tags, with how many posts have each, most-used first.
+    pub fn get_tag_counts(data: &Database, limit: usize) -> Vec<(String, i64)> {
+        let response = data.at(&["tag_counts"]).ok().map(|n| {
+            let mut n = n.with_params();
+            let url = Arc::get_mut(&mut n.url).unwrap();
+            url.set_query(Some(&format!("orderBy={}&limitToLast={}", "\"$value\"", limit)));
+            n.get().ok()
+        }).flatten();
+        let counts = response.map(|r| from_str::<HashMap<String, i64>>(&r.body).ok()).flatten().unwrap_or_default();
+        let mut counts: Vec<(String, i64)> = counts.into_iter().filter(|(_, n)| *n > 0).collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts
+    }
+    /// Adds a post to a user's saved posts, or (if not `saved`) takes it out; they're under `saved/<first_post_id>/<post_id>` as when they were saved (negated, to list the newest first).
+    /// Only posts that the user can see (given the `key` of a share link) can be saved. Returns whether it worked.
+    pub fn save(self: &Post, data: &Database, user: &str, user_first_post_id: &str, key: &str, saved: bool) -> bool {
+        if saved && !self.visible_to(user, key) { return false };
+        let node = data.at(&["saved", user_first_post_id, &self.id]).ok();
+        let r = if saved {
+            node.map(|n| n.set(&(-timestamp()).to_string()).ok()).flatten()
+        } else {
+            node.map(|n| n.delete("").ok()).flatten()
+        };
+        r.map(|r| r.code / 100 == 2).unwrap_or(false)
+    }
+    /// Gets the specified IDs of a user's saved posts, most-recently-saved first.
+    pub fn get_saved(data: &Database, user_first_post_id: &str, start: usize, end: usize) -> Vec<String> {
+        let response = data.at(&["saved", user_first_post_id]).ok().map(|n| {
+            let mut n = n.with_params();
+            let url = Arc::get_mut(&mut n.url).unwrap();
+            url.set_query(Some(&format!("orderBy={}&limitToFirst={}", "\"$value\"", end)));
+            n.get().ok()
+        }).flatten();
+        let saved = response.map(|r| from_str::<HashMap<String, i64>>(&r.body).ok()).flatten().unwrap_or_default();
+        let mut ids: Vec<String> = saved.keys().cloned().collect();
+        ids.sort_by_key(|id| saved[id]);
+        ids.into_iter().skip(start).map(|id| if id == "_" { String::new() } else { id }).collect()
+    }
+    /// Gets how many posts a user has saved.
+    pub fn get_saved_count(data: &Database, user_first_post_id: &str) -> i64 {
+        let r = data.at(&["saved", user_first_post_id]).ok().map(|n| n.with_params().shallow(true).get().ok()).flatten();
+        r.map(|r| from_str::<HashMap<String, bool>>(&r.body).ok()).flatten().map(|m| m.len() as i64).unwrap_or(0)
+    }
+    /// Makes a user follow another (by `access_hash`), or (if not `following`) unfollow; they're under `following/<first_post_id>/<sanitized access_hash>` as the access hash.
+    /// Returns whether it worked (users can't follow themselves, nor users that don't exist).
+    pub fn follow(data: &Database, user: &str, user_first_post_id: &str, access_hash: &str, following: bool) -> bool {
+        let node = data.at(&["following", user_first_post_id, &access_hash.replace(|c:char| !c.is_ascii_alphanumeric(), "_")]).ok();
+        let r = if following {
+            if access_hash == access_token_hash(user) || data.get_first_post(access_hash)().is_none() { return false };
+            node.map(|n| n.set(&json!(access_hash).to_string()).ok()).flatten()
+        } else {
+            node.map(|n| n.delete("").ok()).flatten()
+        };
+        r.map(|r| r.code / 100 == 2).unwrap_or(false)
+    }
+    /// Gets the access hashes of the users that a user follows.
+    pub fn get_followed(data: &Database, user_first_post_id: &str) -> Vec<String> {
+        let r = data.at(&["following", user_first_post_id]).ok().map(|n| n.get().ok()).flatten();
+        r.map(|r| from_str::<HashMap<String, String>>(&r.body).ok()).flatten().map(|m| m.into_values().collect()).unwrap_or_default()
+    }
+    /// Gets the specified IDs of listed posts by the users that a user follows, newest first.
+    /// (Each followed user's newest `end` are merged.)
+    pub fn get_feed(data: &Database, user_first_post_id: &str, start: usize, end: usize) -> Vec<String> {
+        let responses: Vec<_> = Post::get_followed(data, user_first_post_id).iter().map(|hash| {
+            let body: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+            let body2 = body.clone();
+            let handle = Post::listed_by(data, hash, end).map(|n| n.get_async(move |r| {
+                *body2.lock().unwrap() = r.ok().map(|r| r.body);
+            }));
+            (handle, body)
+        }).collect();
+        let mut listed: Vec<(i64, String)> = vec![];
+        for (handle, body) in responses {
+            handle.map(|h| h.join().unwrap());
+            listed.extend(listed_entries(body.lock().unwrap().take()));
+        }
+        listed.sort();
+        listed.into_iter().skip(start).take(end.saturating_sub(start)).map(|(_, id)| id).collect()
+    }
+    /// Gets how many listed posts the users that a user follows have.
+    pub fn get_feed_length(data: &Database, user_first_post_id: &str) -> i64 {
+        Post::get_followed(data, user_first_post_id).iter().map(|hash| Post::get_listed_count(data, hash) as i64).sum()
+    }
+    /// Subscribes a user to this post's thread, to be notified of every post added anywhere below it; or (if not `subscribed`) unsubscribes them. Subscribers are under `subscribers/<post_id>/<first_post_id>`.
+    /// Only posts that the user can see (given the `key` of a share link) can be subscribed to. Returns whether it worked.
+    pub fn subscribe(self: &Post, data: &Database, user: &str, user_first_post_id: &str, key: &str, subscribed: bool) -> bool {
+        if subscribed && !self.visible_to(user, key) { return false };
+        let node = data.at(&["subscribers", &self.id, user_first_post_id]).ok();
+        let r = if subscribed {
+            node.map(|n| n.set("true").ok()).flatten()
+        } else {
+            node.map(|n| n.delete("").ok()).flatten()
+        };
+        r.map(|r| r.code / 100 == 2).unwrap_or(false)
+    }
+    /// Tells everyone subscribed to this post's parent, or to any post above it, that it was added (except its author, and each subscriber only once).
+    /// Notifications are pushed to `notifications/<first_post_id>` as `{ post_id, thread_id }` (`thread_id` being the post subscribed to), with `unread_notifications/<first_post_id>` counting them.
+    fn notify_subscribers(self: &Post, data: &Database) {
+        let mut notified: HashSet<String> = data.get_first_post(&self.access_hash)().into_iter().collect();
+        let mut handles: Vec<JoinHandle<()>> = vec![];
+        let mut id = self.parent_id.clone();
+        loop {
+            let r = data.at(&["subscribers", &id]).ok().map(|n| n.with_params().shallow(true).get().ok()).flatten();
+            let subscribers = r.map(|r| from_str::<HashMap<String, bool>>(&r.body).ok()).flatten().unwrap_or_default();
+            for subscriber in subscribers.into_keys() {
+                if !notified.insert(subscriber.clone()) { continue };
+                let notification = json!({ "post_id": self.id, "thread_id": id }).to_string();
+                data.at(&["notifications", &subscriber]).ok().map(|node| handles.push(node.push_async(&notification, |_| ())));
+                atomic_update(data, &["unread_notifications", &subscriber], 0i64, |v| v+1);
+            }
+            match data.read(vec![&id]).pop().flatten() {
+                Some(post) if post.parent_id != post.id => id = post.parent_id,
+                _ => break, // The root.
+            }
+        }
+        for handle in handles { handle.join().unwrap(); }
+    }
+    /// Gets the specified IDs of the posts that a user was notified of, newest first.
+    pub fn get_notifications(data: &Database, user_first_post_id: &str, start: usize, end: usize) -> Vec<String> {
+        let response = data.at(&["notifications", user_first_post_id]).ok().map(|n| {
+            let mut n = n.with_params();
+            let url = Arc::get_mut(&mut n.url).unwrap();
+            url.set_query(Some(&format!("orderBy={}&limitToLast={}", "\"$key\"", end)));
+            n.get().ok()
+        }).flatten();
+        #[derive(Deserialize)]
+        struct Notification { post_id: String }
+        let notifications = response.map(|r| from_str::<HashMap<String, Notification>>(&r.body).ok()).flatten().unwrap_or_default();
+        let mut notifications: Vec<(String, String)> = notifications.into_iter().map(|(key, n)| (key, n.post_id)).collect();
+        notifications.sort_by(|a, b| b.0.cmp(&a.0)); // Push IDs sort by time.
+        notifications.into_iter().skip(start).map(|(_, id)| id).collect()
+    }
+    /// Gets how many notifications a user has: all of them, or just the `unread` ones.
+    pub fn get_notification_count(data: &Database, user_first_post_id: &str, unread: bool) -> i64 {
+        if unread {
+            data.at(&["unread_notifications", user_first_post_id]).ok().map(|n| n.get().ok()).flatten().map(|r| from_str::<i64>(&r.body).ok()).flatten().unwrap_or(0)
+        } else {
+            let r = data.at(&["notifications", user_first_post_id]).ok().map(|n| n.with_params().shallow(true).get().ok()).flatten();
+            r.map(|r| from_str::<HashMap<String, bool>>(&r.body).ok()).flatten().map(|m| m.len() as i64).unwrap_or(0)
+        }
+    }
+    /// Marks all of a user's notifications as read.
+    pub fn read_notifications(data: &Database, user_first_post_id: &str) {
+        data.at(&["unread_notifications", user_first_post_id]).ok().map(|n| n.delete("").ok());
+    }
+    /// Whether this post is in its parent's child-list.
+    fn listed(self: &Post) -> bool {
+        !self.draft && self.visibility == Visibility::Public
+    }
 
-    /// Returns `{ content, post_reward, user_reward, parent_id, children_rights, access_hash, human_readable_url, logged_in }` as a JSON object, eventually. (`.to_string()` will convert it to a JSON string.)
+    /// Returns `{ content, post_reward, user_reward, saved, subscribed, parent_id, children_rights, access_hash, human_readable_url, slug, share_url, visibility, tags, draft, created, publish_at, expires_at, burn_after_reading, logged_in }` as a JSON object, eventually. (`.to_string()` will convert it to a JSON string.)
     /// 
     /// Despite the signature, the result contains no error, only different paths depending on whether parallelization is possible; consider using `to_json_sync` if no parallelization is OK.
     /// 
-    /// `content` and `parent_id` and `human_readable_url` are strings, rewards are integers, `children_rights` is 'none'|'itself'|'all', `access_hash` is what the owner's access token must hash to, `logged_in` is a boolean.
+    /// `content` and `parent_id` and `human_readable_url` (a path) and `slug` (the end of that path, if assigned) and `share_url` (the path with the key of a private post) are strings, `visibility` is 'public'|'unlisted'|'private', `tags` is an array of strings, rewards are integers, `children_rights` is 'none'|'itself'|'all', `access_hash` is what the owner's access token must hash to, `saved` and `subscribed` (whether the user saved it, and subscribed to its thread), `draft`, `burn_after_reading` and `logged_in` are booleans, `created`, `publish_at` and `expires_at` are Unix timestamps (the last two 0 if not scheduled/expiring).
     pub fn to_json(self: &Post, data: &Database, user_first_post_id: Option<&str>) -> Result<JsonValue, Box<dyn FnOnce()->JsonValue>> {
         let logged_in = user_first_post_id.is_some();
+        let url = "/post/".to_owned() + if &self.human_readable_url == "" {
+            &self.id
+        }  else {
+            &self.human_readable_url
+        };
         let json_value = json!({
             "id": self.id,
             "content": self.content,
             "post_reward": self.reward,
             "user_reward": 0i8,
+            "saved": false,
+            "subscribed": false,
             "children_length": 0i64,
             "parent_id": self.parent_id,
             "children_rights": self.children_rights.to_string(),
             "access_hash": self.access_hash,
-            "human_readable_url": "/post/".to_owned() + if &self.human_readable_url == "" {
-                &self.id
-            }  else {
-                &self.human_readable_url
-            },
+            "human_readable_url": url,
+            "slug": self.human_readable_url,
+            "share_url": if self.share_key != "" { format!("{}?key={}", url, self.share_key) } else { url.clone() },
+            "visibility": self.visibility,
+            "tags": self.tags,
+            "draft": self.draft,
+            "created": -self.reverse_date_created,
+            "publish_at": self.publish_at,
+            "expires_at": self.expires_at,
+            "burn_after_reading": self.burn_after_reading,
             "logged_in": logged_in,
         });
         let value0: Arc<Mutex<JsonValue>> = Arc::new(Mutex::new(json_value));
@@ -229,6 +962,22 @@ impl Post {
                 (*l).as_object_mut().unwrap().insert("user_reward".to_owned(), json!(user_reward));
             }))
         } else { None };
+        let handle3 = if logged_in {
+            let value3 = value0.clone();
+            data.at(&["saved", user_first_post_id.unwrap(), &self.id]).ok().map(|n| n.get_async(move |r| {
+                let saved = r.ok().map(|r| from_str::<Option<i64>>(&r.body).ok()).flatten().flatten().is_some();
+                let mut l = value3.lock().unwrap();
+                (*l).as_object_mut().unwrap().insert("saved".to_owned(), json!(saved));
+            }))
+        } else { None };
+        let handle4 = if logged_in {
+            let value4 = value0.clone();
+            data.at(&["subscribers", &self.id, user_first_post_id.unwrap()]).ok().map(|n| n.get_async(move |r| {
+                let subscribed = r.ok().map(|r| from_str::<Option<bool>>(&r.body).ok()).flatten().flatten().unwrap_or(false);
+                let mut l = value4.lock().unwrap();
+                (*l).as_object_mut().unwrap().insert("subscribed".to_owned(), json!(subscribed));
+            }))
+        } else { None };
         let value2 = value0.clone();
         let handle2 = data.at(&["children_length", &self.id]).ok().map(|n| n.get_async(move |r| {
             let len = r.ok().map(|r| from_str::<i64>(&r.body).ok()).flatten().unwrap_or(0i64);
@@ -238,6 +987,8 @@ impl Post {
         Err(Box::new(move || {
             handle1.map(|h| h.join());
             handle2.map(|h| h.join());
+            handle3.map(|h| h.join());
+            handle4.map(|h| h.join());
             Arc::try_unwrap(value0).unwrap().into_inner().unwrap()
         }))
     }
@@ -340,4 +1091,58 @@ impl<'de> Deserialize<'de> for CanPost {
     where D: serde::Deserializer<'de> {
         deserializer.deserialize_str(CanPostVisitor)
     }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn burning(visibility: Visibility) -> Post {
+        Post{ access_hash: access_token_hash("author"), burn_after_reading: true, visibility, share_key: "k".to_owned(), ..Post::new_public(None, "Secret".to_owned()) }
+    }
+
+    #[test]
+    fn burns_only_for_readers() {
+        assert!(burning(Visibility::Public).burns_for("", ""));
+        assert!(!burning(Visibility::Public).burns_for("author", ""));
+        assert!(!burning(Visibility::Private).burns_for("someone", ""));
+        assert!(!burning(Visibility::Private).burns_for("someone", "guess"));
+        assert!(burning(Visibility::Private).burns_for("someone", "k"));
+        assert!(!Post{ draft: true, ..burning(Visibility::Public) }.burns_for("someone", ""));
+        assert!(!Post{ expires_at: 1, ..burning(Visibility::Public) }.burns_for("someone", "")); // Already burning.
+    }
+
+    #[test]
+    fn maps_only_lasting_posts() {
+        let post = Post{ access_hash: access_token_hash("author"), ..Post::new_public(None, "Hello".to_owned()) };
+        assert!(post.in_sitemap());
+        assert!(Post{ access_hash: "".to_owned(), ..post.clone() }.in_sitemap()); // Anonymized.
+        assert!(!Post{ access_hash: "".to_owned(), content: TOMBSTONE.to_owned(), ..post.clone() }.in_sitemap());
+        assert!(!Post{ expires_at: 1, ..post.clone() }.in_sitemap());
+        assert!(!Post{ burn_after_reading: true, ..post.clone() }.in_sitemap());
+        assert!(!Post{ visibility: Visibility::Unlisted, ..post }.in_sitemap());
+    }
+
+    #[test]
+    fn opens_replies_at_most_like_parents() {
+        use Visibility::*;
+        assert!(Public.max(Public) == Public);
+        assert!(Public.max(Unlisted) == Unlisted);
+        assert!(Private.max(Unlisted) == Private);
+        assert!(Unlisted.max(Private) == Private);
+        assert!(Unlisted.max(Public) == Unlisted);
+    }
+
+    #[test]
+    fn normalizes_tags() {
+        assert_eq!(normalize_tag(" #Web-Dev_2 "), Some("web-dev_2".to_owned()));
+        assert_eq!(normalize_tag("##rust"), Some("rust".to_owned()));
+        assert_eq!(normalize_tag("#"), None);
+        assert_eq!(normalize_tag("two words"), None);
+        assert_eq!(normalize_tag("café"), None);
+        assert_eq!(normalize_tag(&"a".repeat(32)), Some("a".repeat(32)));
+        assert_eq!(normalize_tag(&"a".repeat(33)), None);
+    }
 }
\ No newline at end of file
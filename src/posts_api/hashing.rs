@@ -21,4 +21,38 @@ where T:Iterator, <T as Iterator>::Item: AsRef<[u8]> {
 /// Hashes username+password info (an access token), so that we don't store them, and attackers can't realistically guess them.
 pub fn access_token_hash(access: &str) -> String {
     hash(["saltghdcexg".as_bytes(), access.as_bytes(), "nhlfjeryhbbugvtj6vtt6i67vtiv998".as_bytes()].iter())
+}
+
+/// Hashes a file's content, to name it by that.
+pub fn content_hash(bytes: &[u8]) -> String {
+    hash([bytes].iter())
+}
+
+/// Signs a message with a secret key (HMAC-SHA256), so that whoever shares the key can tell that it's from us, and unaltered.
+/// Example:
+/// ```
+/// println!("HMAC-SHA256: {}", signature("secret", b"{}"));
+/// // HMAC-SHA256: 77325902caca812dc259733aacd046b73817372c777b8d95b402647474516e13
+/// ```
+pub fn signature(key: &str, message: &[u8]) -> String {
+    use hmac::{Hmac, Mac, NewMac};
+    let mut mac = Hmac::<sha2::Sha256>::new_varkey(key.as_bytes()).unwrap(); // Any key length works.
+    mac.update(message);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_like_hmac_sha256() {
+        // RFC 4231, test cases 1 and 2.
+        assert_eq!(signature(&String::from_utf8(vec![0x0b; 20]).unwrap(), b"Hi There"), "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7");
+        assert_eq!(signature("Jefe", b"what do ya want for nothing?"), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        assert_eq!(signature("", b""), "b613679a0814d9ec772f95d778c35fc5ff1697c493715653c6c712144292c5ad");
+        assert_ne!(signature("secret", b"{}"), signature("secret", b"{} "));
+    }
 }
\ No newline at end of file
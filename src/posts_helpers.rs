@@ -4,13 +4,18 @@
 
 use std::sync::Arc;
 
+mod markdown;
+mod highlight;
+mod math;
+pub use markdown::{hashtags, links, render as markdown_to_html};
 use crate::posts_store::Database;
-use crate::posts_api::access_token_hash;
+use crate::posts_api::{access_token_hash, is_moderator, site_url, UNREAD_PLACEHOLDER};
 use crate::posts_api::Post;
+use crate::webhooks;
+use crate::webmention;
 
 use handlebars::{HelperDef, Helper, Handlebars, Context, RenderContext, ScopedJson, RenderError, JsonValue};
 use serde_json::json;
-use pulldown_cmark::{Parser, html};
 
 
 
@@ -21,16 +26,35 @@ const PAGE_LEN: i64 = 32;
 pub enum Which {
     // Viewing.
     // (All this authentication is a LOT of hashing and DB lookups per page-view. So uncivilized.)
-    GetPostById, // post_id, user → post
+    GetPostById, // post_id, user, [key] → post (`key` is from a private post's share link)
     GetNotTopLevel, // post → bool
     GetPostReward, // post → num
     GetUserReward, // post, num → bool (checks equality of reward and num, for coloring buttons)
+    GetSaved, // post → bool (whether the user saved it)
+    GetSubscribed, // post → bool (whether the user subscribed to its thread)
     GetEditable, // post, user → bool
     GetPostable, // post, user → bool
+    GetMovable, // post, user → bool (for authors and moderators)
+    GetWebhooks, // post, user → array<{ id, url, secret }> (only for the author)
+    GetWebhookDeliveries, // post, user → array<{ hook_id, url, event, date, attempts, status, error }> (the last few, newest first; only for the author)
+    GetWebmentions, // post → array<{ source, title, date }> (pages elsewhere that link to it, newest first)
     GetParentId, // post → post_id
     GetSummary, // post → string (the first line of content)
     GetContent, // post → string (the whole Markdown content, parsed into HTML)
+    GetMeta, // post → { title, description, url, image, card } (for OpenGraph/Twitter-card tags; `image` may be "")
     GetPostChildren, // post_id, user, page_index, length → array<post> (sorted by descending reward) (`length` should be `post.children_length`)
+    GetDrafts, // user → array<post> (the user's own drafts)
+    GetTagged, // tag, user, page_index, sort → array<post> (sorted by descending reward, or newest first if `sort` is "new")
+    GetTagLength, // tag → num (how many posts have it)
+    GetTagCloud, // → array<{ name, count, size }> (the most-used tags, `size` being 1 to 5)
+    GetSavedPosts, // user, page_index → array<post> (most-recently-saved first)
+    GetSavedLength, // user → num (how many posts the user saved)
+    GetFollowing, // access_hash, user → bool (whether the user follows them)
+    GetFeed, // user, page_index → array<post> (by the users that the user follows, newest first)
+    GetFeedLength, // user → num
+    GetNotifications, // user, page_index → array<post> (added to threads that the user subscribed to, newest first)
+    GetNotificationLength, // user → num
+    GetUnreadNotificationLength, // user → num
     GetUserFirstPostId, // user → post_id
     IsLoggedIn, // user → bool
     Plus1, // num → num (for recursion, to increment `depth`)
@@ -74,6 +98,10 @@ impl HelperDef for PostHelper {
             json!(perhaps_promises.drain(..).map(|p| match p {
                 Ok(v) => v,
                 Err(closure) => closure(),
+            }).map(|mut post| {
+                // Posts that burn after reading are only read on their own page, where opening them counts.
+                if post["burn_after_reading"] == json!(true) { post["content"] = json!(UNREAD_PLACEHOLDER) };
+                post
             }).collect::<JsonValue>())
         };
         let f = |x| Ok(Some(ScopedJson::from(x)));
@@ -83,6 +111,7 @@ impl HelperDef for PostHelper {
                     let post = |id: String| self.data.read(vec!(&id)).pop().unwrap();
                     let first_post_id = auth(str_arg(1));
                     match post(str_arg(0).to_string()) {
+                        Some(ref post) if !post.visible_to(str_arg(1), h.param(2).map(|p| p.value().as_str()).flatten().unwrap_or("")) => json!(null),
                         Some(ref post) => post.to_json_sync(&self.data, first_post_id().as_deref()),
                         None => json!(null),
                     }
@@ -110,10 +139,33 @@ impl HelperDef for PostHelper {
                     None => json!(0i64 == expect),
                 }
             },
+            Which::GetSaved => match arg(0).get("saved") {
+                Some(v) => json!(v.as_bool().unwrap_or(false)),
+                None => json!(false),
+            },
+            Which::GetSubscribed => match arg(0).get("subscribed") {
+                Some(v) => json!(v.as_bool().unwrap_or(false)),
+                None => json!(false),
+            },
             Which::GetEditable => match arg(0).get("access_hash").map(|v| v.as_str()) {
                 Some(Some(v)) => json!(v == access_token_hash(str_arg(1))),
                 _ => json!(false),
             },
+            Which::GetMovable => match arg(0).get("access_hash").map(|v| v.as_str()) {
+                Some(Some(v)) => json!(v != "" && v == access_token_hash(str_arg(1)) || is_moderator(str_arg(1))),
+                _ => json!(false),
+            },
+            Which::GetWebhooks | Which::GetWebhookDeliveries => match (arg(0).get("id").map(|v| v.as_str()).flatten(), arg(0).get("access_hash").map(|v| v.as_str()).flatten()) {
+                (Some(id), Some(hash)) if hash != "" && hash == access_token_hash(str_arg(1)) => match self.which {
+                    Which::GetWebhooks => json!(webhooks::hooks(&self.data, id).iter().map(|h| json!({ "id": h.id, "url": h.url, "secret": h.secret })).collect::<Vec<JsonValue>>()),
+                    _ => json!(webhooks::deliveries(&self.data, id, 20)),
+                },
+                _ => json!([]),
+            },
+            Which::GetWebmentions => match arg(0).get("id").map(|v| v.as_str()).flatten() {
+                Some(id) => json!(webmention::mentions(&self.data, id)),
+                None => json!([]),
+            },
             Which::GetPostable => {
                 let user = access_token_hash(str_arg(1));
                 match arg(0).get("children_rights") {
@@ -136,13 +188,7 @@ impl HelperDef for PostHelper {
                 _ => json!(""),
             },
             Which::GetSummary => match arg(0).get("content").map(|v| v.as_str()) {
-                Some(Some(v)) => {
-                    let s = match v.split_once('\n') {
-                        Some((line, _rest)) => line,
-                        None => v,
-                    };
-                    json!(s.trim_start_matches('#').trim())
-                },
+                Some(Some(v)) => json!(summary(v)),
                 _ => json!(""),
             },
             Which::GetContent => match arg(0).get("content").map(|v| v.as_str()) {
@@ -151,13 +197,24 @@ impl HelperDef for PostHelper {
                         Some((_line, rest)) => rest,
                         None => "",
                     };
-                    let parser = Parser::new(s);
-                    let mut html_output = String::new();
-                    html::push_html(&mut html_output, parser);
-                    json!(ammonia::clean(&*html_output))
+                    let id = arg(0).get("id").map(|v| v.as_str()).flatten().unwrap_or("");
+                    json!(markdown::render(id, s))
                 },
                 _ => json!(""),
             },
+            Which::GetMeta => {
+                let content = arg(0).get("content").map(|v| v.as_str()).flatten().unwrap_or("");
+                let rest = content.split_once('\n').map(|(_line, rest)| rest).unwrap_or("");
+                let url = arg(0).get("human_readable_url").map(|v| v.as_str()).flatten().unwrap_or("");
+                let image = markdown::first_image(rest).map(|i| absolute_url(&i)).unwrap_or_default();
+                json!({
+                    "title": summary(content),
+                    "description": excerpt(&markdown::plain_text(rest), 200),
+                    "url": absolute_url(url),
+                    "card": if image.is_empty() { "summary" } else { "summary_large_image" },
+                    "image": image,
+                })
+            },
             Which::GetPostChildren => {
                 let id = str_arg(0);
                 let first_post_id = auth(str_arg(1));
@@ -166,6 +223,93 @@ impl HelperDef for PostHelper {
                 let ch = Post::get_children_by_reward(id, &self.data, start, end, len as usize).unwrap();
                 post_ids_to_post_json(ch, first_post_id().as_deref())
             },
+            Which::GetDrafts => {
+                let user = str_arg(0);
+                let first_post_id = auth(user);
+                let ids = Post::get_drafts(&self.data, &access_token_hash(user));
+                post_ids_to_post_json(ids, first_post_id().as_deref())
+            },
+            Which::GetTagged => {
+                let first_post_id = auth(str_arg(1));
+                let (start, end) = page(i64_arg(2));
+                let by_date = arg(3).as_str() == Some("new");
+                let ids = Post::get_tagged(str_arg(0), &self.data, start, end, by_date);
+                post_ids_to_post_json(ids, first_post_id().as_deref())
+            },
+            Which::GetTagLength => json!(Post::get_tag_count(&self.data, str_arg(0))),
+            Which::GetTagCloud => {
+                let counts = Post::get_tag_counts(&self.data, 50);
+                let max = counts.first().map(|(_, n)| *n).unwrap_or(1) as f64;
+                json!(counts.iter().map(|(name, count)| json!({
+                    "name": name,
+                    "count": count,
+                    // Logarithmic, so that a few popular tags don't make all others tiny.
+                    "size": 1 + (4.0 * (*count as f64).ln() / max.ln().max(1.0)).round() as i64,
+                })).collect::<Vec<JsonValue>>())
+            },
+            Which::GetSavedPosts => {
+                let user = str_arg(0);
+                let (start, end) = page(i64_arg(1));
+                match auth(user)() {
+                    Some(first_post_id) => {
+                        let ids = Post::get_saved(&self.data, &first_post_id, start, end);
+                        let mut posts = post_ids_to_post_json(ids, Some(&first_post_id));
+                        // A saved post may have been unpublished since.
+                        let hash = access_token_hash(user);
+                        for post in posts.as_array_mut().unwrap() {
+                            if post["draft"] == json!(true) && post["access_hash"] != json!(hash) { *post = json!(null) };
+                        }
+                        posts
+                    },
+                    None => json!([]),
+                }
+            },
+            Which::GetSavedLength => match auth(str_arg(0))() {
+                Some(first_post_id) => json!(Post::get_saved_count(&self.data, &first_post_id)),
+                None => json!(0i64),
+            },
+            Which::GetFollowing => match auth(str_arg(1))() {
+                Some(first_post_id) => json!(Post::get_followed(&self.data, &first_post_id).iter().any(|h| h == str_arg(0))),
+                None => json!(false),
+            },
+            Which::GetFeed => {
+                let user = str_arg(0);
+                let (start, end) = page(i64_arg(1));
+                match auth(user)() {
+                    Some(first_post_id) => {
+                        let ids = Post::get_feed(&self.data, &first_post_id, start, end);
+                        let mut posts = post_ids_to_post_json(ids, Some(&first_post_id));
+                        // Only what's listed anyway.
+                        for post in posts.as_array_mut().unwrap() {
+                            if post["draft"] == json!(true) || post["visibility"] != json!("public") { *post = json!(null) };
+                        }
+                        posts
+                    },
+                    None => json!([]),
+                }
+            },
+            Which::GetFeedLength => match auth(str_arg(0))() {
+                Some(first_post_id) => json!(Post::get_feed_length(&self.data, &first_post_id)),
+                None => json!(0i64),
+            },
+            Which::GetNotifications => {
+                let (start, end) = page(i64_arg(1));
+                match auth(str_arg(0))() {
+                    Some(first_post_id) => {
+                        let ids = Post::get_notifications(&self.data, &first_post_id, start, end);
+                        post_ids_to_post_json(ids, Some(&first_post_id))
+                    },
+                    None => json!([]),
+                }
+            },
+            Which::GetNotificationLength => match auth(str_arg(0))() {
+                Some(first_post_id) => json!(Post::get_notification_count(&self.data, &first_post_id, false)),
+                None => json!(0i64),
+            },
+            Which::GetUnreadNotificationLength => match auth(str_arg(0))() {
+                Some(first_post_id) => json!(Post::get_notification_count(&self.data, &first_post_id, true)),
+                None => json!(0i64),
+            },
             Which::GetUserFirstPostId => {
                 json!(self.data.get_first_post(&str_arg(0))().unwrap_or_else(|| "".to_owned()))
             },
@@ -206,6 +350,38 @@ impl HelperDef for PostHelper {
     }
 }
 
+/// The first line of a post's content, without the heading's `#`s.
+fn summary(content: &str) -> &str {
+    let s = match content.split_once('\n') {
+        Some((line, _rest)) => line,
+        None => content,
+    };
+    s.trim_start_matches('#').trim()
+}
+
+/// Shortens text to at most `len` chars (plus an ellipsis), preferring to cut at a space.
+fn excerpt(text: &str, len: usize) -> String {
+    match text.char_indices().nth(len) {
+        None => text.to_owned(),
+        Some((end, _)) => {
+            let cut = &text[..end];
+            let cut = cut.rsplit_once(' ').map(|(before, _)| before).unwrap_or(cut);
+            cut.trim_end().to_owned() + "…"
+        },
+    }
+}
+
+/// Makes a site-relative URL absolute, with the site's address (see `site_url`).
+fn absolute_url(url: &str) -> String {
+    if url.starts_with('/') {
+        site_url() + url
+    } else {
+        url.to_owned()
+    }
+}
+
+
+
 impl PostHelper {
     pub fn register<'reg>(templates: &mut Handlebars<'reg>, d: &Arc<Database>) {
         let mut f = |s, t| templates.register_helper(s, Box::new(PostHelper{ which:t, data:d.clone() }));
@@ -213,12 +389,31 @@ impl PostHelper {
         f("GetNotTopLevel", Which::GetNotTopLevel);
         f("GetPostReward", Which::GetPostReward);
         f("GetUserReward", Which::GetUserReward);
+        f("GetSaved", Which::GetSaved);
+        f("GetSubscribed", Which::GetSubscribed);
         f("GetEditable", Which::GetEditable);
         f("GetPostable", Which::GetPostable);
+        f("GetMovable", Which::GetMovable);
+        f("GetWebhooks", Which::GetWebhooks);
+        f("GetWebhookDeliveries", Which::GetWebhookDeliveries);
+        f("GetWebmentions", Which::GetWebmentions);
         f("GetParentId", Which::GetParentId);
         f("GetSummary", Which::GetSummary);
         f("GetContent", Which::GetContent);
+        f("GetMeta", Which::GetMeta);
         f("GetPostChildren", Which::GetPostChildren);
+        f("GetDrafts", Which::GetDrafts);
+        f("GetTagged", Which::GetTagged);
+        f("GetTagLength", Which::GetTagLength);
+        f("GetTagCloud", Which::GetTagCloud);
+        f("GetSavedPosts", Which::GetSavedPosts);
+        f("GetSavedLength", Which::GetSavedLength);
+        f("GetFollowing", Which::GetFollowing);
+        f("GetFeed", Which::GetFeed);
+        f("GetFeedLength", Which::GetFeedLength);
+        f("GetNotifications", Which::GetNotifications);
+        f("GetNotificationLength", Which::GetNotificationLength);
+        f("GetUnreadNotificationLength", Which::GetUnreadNotificationLength);
         f("GetUserFirstPostId", Which::GetUserFirstPostId);
         f("IsLoggedIn", Which::IsLoggedIn);
         f("Plus1", Which::Plus1);
@@ -0,0 +1,77 @@
+//! Highlights the syntax of code blocks on the server, because the site has no JS to do it on the client.
+
+
+
+use std::sync::OnceLock;
+
+use pulldown_cmark::escape::escape_html;
+use syntect::parsing::SyntaxSet;
+use syntect::html::{ClassedHTMLGenerator, ClassStyle};
+use syntect::util::LinesWithEndings;
+
+
+
+/// The prefix of every class that highlighting emits; `static/post.css` styles these.
+pub const CLASS_PREFIX: &str = "hl-";
+
+/// Loading all syntax definitions takes a while, so it's only done once.
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+
+
+/// Renders a fenced code block as HTML, with `<span class="hl-…">` around each token.
+///
+/// `lang` is the block's info string, such as `rust` or `py title="x"`; unknown languages are left unhighlighted.
+///
+/// ```
+/// assert_eq!(code_block("nope", "a < b\n"), "<pre><code class=\"language-nope\">a &lt; b\n</code></pre>\n");
+/// assert!(code_block("rust", "fn main() {}\n").contains("<span class=\"hl-storage hl-type hl-function hl-rust\">fn</span>"));
+/// ```
+pub fn code_block(lang: &str, code: &str) -> String {
+    let lang = lang.split(|c: char| c.is_whitespace() || c == ',').next().unwrap_or("");
+    let mut escaped_lang = String::new();
+    escape_html(&mut escaped_lang, lang).unwrap();
+    let syntaxes = syntaxes();
+    let highlighted = syntaxes.find_syntax_by_token(lang).map(|syntax| {
+        let mut gen = ClassedHTMLGenerator::new_with_class_style(syntax, syntaxes, ClassStyle::SpacedPrefixed { prefix: CLASS_PREFIX });
+        for line in LinesWithEndings::from(code) {
+            gen.parse_html_for_line_which_includes_newline(line).ok()?;
+        }
+        Some(gen.finalize())
+    }).flatten();
+    let body = highlighted.unwrap_or_else(|| {
+        let mut s = String::new();
+        escape_html(&mut s, code).unwrap();
+        s
+    });
+    format!("<pre><code class=\"language-{}\">{}</code></pre>\n", escaped_lang, body)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn highlights_known_languages() {
+        let html = code_block("rust", "fn main() {}\n");
+        assert!(html.starts_with("<pre><code class=\"language-rust\">"));
+        assert!(html.contains("<span class=\"hl-storage hl-type hl-function hl-rust\">fn</span>"));
+        assert!(code_block("py title=\"x\"", "pass\n").starts_with("<pre><code class=\"language-py\">"));
+    }
+
+    #[test]
+    fn escapes_unknown_languages() {
+        assert_eq!(code_block("nope", "a < b\n"), "<pre><code class=\"language-nope\">a &lt; b\n</code></pre>\n");
+        assert_eq!(code_block("\"><script>", "x"), "<pre><code class=\"language-&quot;&gt;&lt;script&gt;\">x</code></pre>\n");
+    }
+
+    #[test]
+    fn leaves_math_in_code_alone() {
+        assert!(crate::posts_helpers::markdown::render("p", "```rust\nlet s = \"$x$\";\n```").contains("$x$"));
+    }
+}
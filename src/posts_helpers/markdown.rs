@@ -0,0 +1,410 @@
+//! Renders post Markdown into sanitized HTML.
+
+
+
+use super::highlight;
+use super::math;
+
+use std::ops::Range;
+
+use pulldown_cmark::{Parser, Options, Event, Tag, CodeBlockKind, CowStr, html};
+use pulldown_cmark::escape::escape_html;
+
+
+
+/// Whether a Markdown extension is enabled, site-wide.
+///
+/// Set the `MARKDOWN_EXTENSIONS` environment variable to a comma-separated subset of `tables,footnotes,strikethrough,tasklists,math` to choose; all are enabled if it is unset, none if it is empty.
+fn enabled(extension: &str) -> bool {
+    match std::env::var("MARKDOWN_EXTENSIONS") {
+        Ok(setting) => setting.split(',').any(|s| s.trim() == extension),
+        Err(_) => true,
+    }
+}
+
+/// The parser options of enabled extensions.
+pub fn options() -> Options {
+    let mut opts = Options::empty();
+    if enabled("tables") { opts.insert(Options::ENABLE_TABLES) };
+    if enabled("footnotes") { opts.insert(Options::ENABLE_FOOTNOTES) };
+    if enabled("strikethrough") { opts.insert(Options::ENABLE_STRIKETHROUGH) };
+    if enabled("tasklists") { opts.insert(Options::ENABLE_TASKLISTS) };
+    opts
+}
+
+
+
+/// Converts Markdown to HTML that is safe to include in a page.
+///
+/// `post_id` namespaces footnote links, so that many posts can be displayed on one page without their footnotes colliding.
+/// Fenced code blocks with a language get highlighted, and `$…$`/`$$…$$` become inline/display math.
+///
+/// ```
+/// // Tables (alignment is kept):
+/// assert_eq!(render("p", "| a | b |\n|:-|-:|\n| 1 | 2 |"), "<table><thead><tr><th align=\"left\">a</th><th align=\"right\">b</th></tr></thead><tbody>\n<tr><td align=\"left\">1</td><td align=\"right\">2</td></tr>\n</tbody></table>\n");
+/// // Footnotes:
+/// assert_eq!(render("p", "Hi[^n]\n\n[^n]: There."), "<p>Hi<sup class=\"footnote-reference\"><a href=\"#fn-p-n\" rel=\"noopener noreferrer\">1</a></sup></p>\n<div class=\"footnote-definition\" id=\"fn-p-n\"><sup class=\"footnote-definition-label\">1</sup>\n<p>There.</p>\n</div>\n");
+/// // Strikethrough:
+/// assert_eq!(render("p", "~~no~~"), "<p><del>no</del></p>\n");
+/// // Task lists (always disabled checkboxes):
+/// assert_eq!(render("p", "- [x] done\n- [ ] not"), "<ul>\n<li><input type=\"checkbox\" checked=\"\" disabled=\"\">\ndone</li>\n<li><input type=\"checkbox\" disabled=\"\">\nnot</li>\n</ul>\n");
+/// // Math:
+/// assert_eq!(render("p", "Area: $\\pi r^2$, not $5 or $10."), "<p>Area: <math><mrow><mi>π</mi><msup><mi>r</mi><mn>2</mn></msup></mrow></math>, not $5 or $10.</p>\n");
+/// // And scripts are still stripped:
+/// assert_eq!(render("p", "<input type=\"text\" onclick=\"alert(1)\"><script>alert(1)</script>"), "<p><input disabled=\"\"></p>\n");
+/// ```
+pub fn render(post_id: &str, md: &str) -> String {
+    let footnote = |label: CowStr| CowStr::from(format!("fn-{}-{}", post_id, label));
+    let (md, formulas) = if enabled("math") { extract_math(md) } else { (md.to_owned(), vec![]) };
+    let restore = |s| restore_math(s, &formulas);
+    let mut code: Option<(String, String)> = None; // (lang, text) of the code block being collected.
+    let mut in_image = false; // An image's text is its `alt`, where formulas stay TeX.
+    let parser = Parser::new_ext(&md, options()).filter_map(|event| match event {
+        Event::Text(text) if in_image => Some(Event::Text(restore(text))),
+        Event::Text(text) if text.contains(MATH_START) && code.is_none() => Some(Event::Html(insert_math(&text, &formulas, true).into())),
+        Event::Start(Tag::Link(t, url, title)) => Some(Event::Start(Tag::Link(t, restore(url), restore(title)))),
+        Event::End(Tag::Link(t, url, title)) => Some(Event::End(Tag::Link(t, restore(url), restore(title)))),
+        Event::Start(Tag::Image(t, url, title)) => {
+            in_image = true;
+            Some(Event::Start(Tag::Image(t, restore(url), restore(title))))
+        },
+        Event::End(Tag::Image(t, url, title)) => {
+            in_image = false;
+            Some(Event::End(Tag::Image(t, restore(url), restore(title))))
+        },
+        Event::FootnoteReference(label) => Some(Event::FootnoteReference(footnote(label))),
+        Event::Start(Tag::FootnoteDefinition(label)) => Some(Event::Start(Tag::FootnoteDefinition(footnote(label)))),
+        Event::End(Tag::FootnoteDefinition(label)) => Some(Event::End(Tag::FootnoteDefinition(footnote(label)))),
+        Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(lang))) if !lang.is_empty() => {
+            code = Some((lang.to_string(), String::new()));
+            None
+        },
+        Event::Text(text) if code.is_some() => {
+            code.as_mut().unwrap().1.push_str(&text);
+            None
+        },
+        Event::End(Tag::CodeBlock(_)) if code.is_some() => {
+            let (lang, text) = code.take().unwrap();
+            Some(Event::Html(highlight::code_block(&lang, &text).into()))
+        },
+        e => Some(e),
+    });
+    let mut html_output = String::new();
+    html::push_html(&mut html_output, parser);
+    sanitizer().clean(&*html_output).to_string()
+}
+
+
+
+/// The text of Markdown, without formatting, on one line.
+///
+/// ```
+/// assert_eq!(plain_text("Some *emphasis*,\nand `code`.\n\n- A list"), "Some emphasis, and code. A list");
+/// ```
+pub fn plain_text(md: &str) -> String {
+    let mut text = String::new();
+    for event in Parser::new_ext(md, options()) {
+        match event {
+            Event::Text(s) | Event::Code(s) => text.push_str(&s),
+            Event::SoftBreak | Event::HardBreak
+            | Event::End(Tag::Paragraph) | Event::End(Tag::Heading(_)) | Event::End(Tag::Item)
+            | Event::End(Tag::CodeBlock(_)) | Event::End(Tag::TableCell) => if !text.ends_with(' ') { text.push(' ') },
+            _ => (),
+        }
+    }
+    text.trim().to_owned()
+}
+
+/// The `#hashtags` in Markdown's text (not in code), without the `#`, as written.
+///
+/// ```
+/// assert_eq!(hashtags("# Title\n\nAbout #rust_lang and #Web.\n\n```\n#include\n```"), vec!["rust_lang", "Web"]);
+/// ```
+pub fn hashtags(md: &str) -> Vec<String> {
+    let mut tags = vec![];
+    let mut text = String::new();
+    let mut scan = |text: &mut String| {
+        let mut rest = &text[..];
+        while let Some(i) = rest.find('#') {
+            let preceded_by_word = rest[..i].chars().last().map(|c| !c.is_whitespace() && c != '(').unwrap_or(false);
+            rest = &rest[i+1..];
+            let len = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-')).unwrap_or(rest.len());
+            if !preceded_by_word && len > 0 { tags.push(rest[..len].to_owned()) };
+            rest = &rest[len..];
+        }
+        text.clear();
+    };
+    let mut in_code = false;
+    for event in Parser::new_ext(md, options()) {
+        match event {
+            // The parser splits text at characters that might be formatting, such as `_`.
+            Event::Text(s) if !in_code => text.push_str(&s),
+            Event::Start(Tag::CodeBlock(_)) => { scan(&mut text); in_code = true },
+            Event::End(Tag::CodeBlock(_)) => in_code = false,
+            _ => scan(&mut text),
+        }
+    }
+    scan(&mut text);
+    tags
+}
+
+/// The URLs that Markdown links to (not images), once each.
+///
+/// ```
+/// assert_eq!(links("[A](https://a.example) and <https://b.example>, [A again](https://a.example) ![](https://c.example/i.png)"), vec!["https://a.example", "https://b.example"]);
+/// ```
+pub fn links(md: &str) -> Vec<String> {
+    let mut urls: Vec<String> = vec![];
+    for event in Parser::new_ext(md, options()) {
+        if let Event::Start(Tag::Link(_, url, _)) = event {
+            if !urls.iter().any(|u| u == &url[..]) { urls.push(url.to_string()) };
+        }
+    }
+    urls
+}
+
+/// The URL of the first image in Markdown, if any.
+pub fn first_image(md: &str) -> Option<String> {
+    Parser::new_ext(md, options()).find_map(|event| match event {
+        Event::Start(Tag::Image(_, url, _)) => Some(url.to_string()),
+        _ => None,
+    })
+}
+
+
+
+/// `ammonia`'s defaults, plus what the Markdown extensions produce: footnote classes & IDs, read-only task-list checkboxes, highlighting classes, and MathML.
+fn sanitizer() -> ammonia::Builder<'static> {
+    let mut b = ammonia::Builder::default();
+    b.add_tags(&["input"])
+        .add_tag_attributes("input", &["checked"])
+        .add_tag_attribute_values("input", "type", &["checkbox"])
+        .set_tag_attribute_value("input", "disabled", "")
+        .add_tag_attributes("div", &["id"])
+        .add_allowed_classes("sup", &["footnote-reference", "footnote-definition-label"])
+        .add_allowed_classes("div", &["footnote-definition"])
+        .id_prefix(Some("fn-"))
+        .add_tag_attributes("span", &["class"])
+        .add_tag_attributes("code", &["class"])
+        .add_tags(&["math", "mrow", "mi", "mn", "mo", "mtext", "mspace", "msub", "msup", "msubsup", "munder", "mover", "munderover", "mfrac", "msqrt", "mroot", "mtable", "mtr", "mtd", "merror"])
+        .add_tag_attributes("math", &["display"])
+        .add_tag_attributes("mi", &["mathvariant"])
+        .add_tag_attributes("mo", &["fence", "stretchy", "largeop", "movablelimits", "form"])
+        .add_tag_attributes("mspace", &["width"])
+        .add_tag_attributes("mfrac", &["linethickness"])
+        .add_tag_attributes("mover", &["accent"])
+        .add_tag_attributes("munder", &["accentunder"])
+        .add_tag_attributes("mtable", &["columnalign"])
+        .attribute_filter(|element, attribute, value| match (element, attribute) {
+            ("span", "class") => Some(only_classes(value, highlight::CLASS_PREFIX).into()),
+            ("code", "class") => Some(only_classes(value, "language-").into()),
+            _ => Some(value.into()),
+        });
+    b
+}
+
+/// Filters a `class` attribute's value, leaving only classes that start with `prefix`.
+fn only_classes(value: &str, prefix: &str) -> String {
+    value.split_whitespace().filter(|c| c.starts_with(prefix)).collect::<Vec<&str>>().join(" ")
+}
+
+
+
+/// Marks the start of a formula's placeholder, which is then its index and `MATH_END`. (Private-use chars, so Markdown treats them as letters.)
+const MATH_START: char = '\u{E000}';
+const MATH_END: char = '\u{E001}';
+
+/// Byte ranges of code and raw HTML, where `$` means nothing. They're in order, and don't overlap.
+fn verbatim_ranges(md: &str) -> Vec<Range<usize>> {
+    Parser::new_ext(md, options()).into_offset_iter().filter_map(|(event, range)| match event {
+        Event::Code(_) | Event::Html(_) | Event::Start(Tag::CodeBlock(_)) => Some(range),
+        _ => None,
+    }).collect()
+}
+
+/// Replaces each formula with a placeholder, so that Markdown parsing won't touch it (`*`s in formulas are not emphasis, for example).
+///
+/// Returns the Markdown with placeholders, and each formula's (is_display, TeX).
+/// Like in Pandoc, `$`s only count if the opening is not followed by whitespace, and the closing is not preceded by whitespace nor followed by a digit. `\$` is a dollar sign.
+/// Formulas end at a blank line or at code, so an opening that isn't closed before one is just a dollar sign.
+fn extract_math(md: &str) -> (String, Vec<(bool, String)>) {
+    let verbatim = verbatim_ranges(md);
+    let bytes = md.as_bytes();
+    let in_verbatim = |i: usize| verbatim.get(verbatim.partition_point(|r| r.end <= i)).filter(|r| r.start <= i).map(|r| r.end);
+    // Where a formula that starts at `start` closes, or else where looking for that stopped.
+    let closing = |start: usize, display: bool| -> Result<usize, usize> {
+        if !display && bytes.get(start).map_or(true, |b| b.is_ascii_whitespace()) { return Err(start) };
+        let mut i = start;
+        while i < bytes.len() {
+            if in_verbatim(i).is_some() || bytes[i..].starts_with(b"\n\n") { return Err(i) }; // Bytes: `i` may be inside a multibyte char.
+            match bytes[i] {
+                b'\\' => i += 1,
+                b'$' if display => if bytes.get(i+1) == Some(&b'$') && i > start { return Ok(i) },
+                b'$' => if i > start && !bytes[i-1].is_ascii_whitespace() && !bytes.get(i+1).map_or(false, |b| b.is_ascii_digit()) { return Ok(i) },
+                _ => (),
+            }
+            i += 1;
+        }
+        Err(bytes.len())
+    };
+    // For inline and display formulas: where looking for a closing last stopped without finding one.
+    // Openings before there would find none either, so they aren't looked from again: that keeps this linear, even with many `$`s.
+    let mut unclosed_until = [0, 0];
+    let (mut out, mut formulas) = (String::new(), vec![]);
+    let (mut i, mut copied) = (0, 0);
+    while i < bytes.len() {
+        if let Some(end) = in_verbatim(i) { i = end; continue };
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'$' => {
+                let display = bytes.get(i+1) == Some(&b'$');
+                let open = if display {2} else {1};
+                let unclosed = &mut unclosed_until[display as usize];
+                let end = if i < *unclosed { None } else { closing(i + open, display).map_err(|stop| *unclosed = stop).ok() };
+                match end {
+                    Some(end) => {
+                        out.push_str(&md[copied..i]);
+                        out.push_str(&format!("{}{}{}", MATH_START, formulas.len(), MATH_END));
+                        formulas.push((display, md[i+open..end].to_owned()));
+                        i = end + open;
+                        copied = i;
+                    },
+                    None => i += open,
+                }
+            },
+            _ => i += 1,
+        }
+    }
+    out.push_str(&md[copied..]);
+    (out, formulas)
+}
+
+/// Replaces placeholders in text with formulas: as MathML if `as_html` (and the rest of the text is escaped), else as the original TeX.
+fn insert_math(text: &str, formulas: &[(bool, String)], as_html: bool) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(MATH_START) {
+        let (before, after) = rest.split_at(start);
+        let after = &after[MATH_START.len_utf8()..];
+        let (index, after) = after.split_once(MATH_END).unwrap_or((after, ""));
+        let formula = index.parse::<usize>().ok().map(|i| formulas.get(i)).flatten();
+        if as_html {
+            escape_html(&mut out, before).unwrap();
+            formula.map(|(display, tex)| out.push_str(&math::to_mathml(tex, *display)));
+        } else {
+            out.push_str(before);
+            formula.map(|(display, tex)| {
+                let delimiter = if *display { "$$" } else { "$" };
+                out.push_str(delimiter);
+                out.push_str(tex);
+                out.push_str(delimiter);
+            });
+        }
+        rest = after;
+    }
+    if as_html { escape_html(&mut out, rest).unwrap() } else { out.push_str(rest) };
+    out
+}
+
+/// Puts the original TeX back into a string that's not displayed as text, such as a link's URL.
+fn restore_math<'a>(s: CowStr<'a>, formulas: &[(bool, String)]) -> CowStr<'a> {
+    if s.contains(MATH_START) { insert_math(&s, formulas, false).into() } else { s }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::posts_api::MAX_CONTENT;
+
+    #[test]
+    fn pads_short_table_rows() {
+        assert_eq!(render("p", "| a | b |\n|-|-|\n| 1 |"), "<table><thead><tr><th>a</th><th>b</th></tr></thead><tbody>\n<tr><td>1</td><td></td></tr>\n</tbody></table>\n");
+    }
+
+    #[test]
+    fn keeps_ids_apart() {
+        assert!(render("a", "x[^1]\n\n[^1]: y").contains("<div class=\"footnote-definition\" id=\"fn-a-1\">"));
+        assert!(render("b", "x[^1]\n\n[^1]: y").contains("<a href=\"#fn-b-1\""));
+        // Posts can't take the page's own IDs, nor footnotes' classes along with others.
+        assert_eq!(render("p", "<div id=\"main\">x</div>"), "<div id=\"fn-main\">x</div>");
+        assert_eq!(render("p", "<sup class=\"footnote-reference evil\">1</sup>"), "<p><sup class=\"footnote-reference\">1</sup></p>\n");
+    }
+
+    #[test]
+    fn strikes_through_with_two_tildes() {
+        assert_eq!(render("p", "~one~ and ~~two~~"), "<p>~one~ and <del>two</del></p>\n");
+    }
+
+    #[test]
+    fn disables_every_checkbox() {
+        assert_eq!(render("p", "- [ ] a\n  - [x] b"), "<ul>\n<li><input type=\"checkbox\" disabled=\"\">\na\n<ul>\n<li><input type=\"checkbox\" checked=\"\" disabled=\"\">\nb</li>\n</ul>\n</li>\n</ul>\n");
+        assert_eq!(render("p", "<input type=\"checkbox\" onchange=\"x()\">"), "<input type=\"checkbox\" disabled=\"\">");
+        assert_eq!(render("p", "- [x]"), "<ul>\n<li>[x]</li>\n</ul>\n"); // Not a task without a text.
+    }
+
+    #[test]
+    fn strips_scripts_and_styles() {
+        assert_eq!(render("p", "[x](javascript:alert(1))"), "<p><a rel=\"noopener noreferrer\">x</a></p>\n");
+        assert_eq!(render("p", "<table><tr><td style=\"color:red\" onclick=\"x()\">x</td></tr></table>"), "<table><tbody><tr><td>x</td></tr></tbody></table>");
+    }
+
+    #[test]
+    fn extracts_plain_text() {
+        assert_eq!(plain_text("# Title\n\n| a | b |\n|-|-|\n| 1 | 2 |"), "Title a b 1 2");
+        assert_eq!(plain_text("A [link](https://a.example) and <b>tags</b>.\n\n```\ncode\n```"), "A link and tags. code");
+        assert_eq!(plain_text("  \n\n"), "");
+    }
+
+    #[test]
+    fn finds_hashtags_in_text_only() {
+        assert_eq!(hashtags("a#b (#c) `#d` [#e](https://a.example/#f)\n\n```\n#g\n```"), vec!["c", "e"]);
+        assert_eq!(hashtags("#under_score #hy-phen #trailing. ##double #"), vec!["under_score", "hy-phen", "trailing", "double"]);
+        assert_eq!(hashtags("# Heading, not a tag\n#tag"), vec!["tag"]);
+    }
+
+    #[test]
+    fn finds_links_once() {
+        assert_eq!(links("[A](https://a.example) and <https://b.example>, [A again](https://a.example) ![](https://c.example/i.png)"), vec!["https://a.example", "https://b.example"]);
+        assert_eq!(links("[ref][1] and [rel](/post/x)\n\n[1]: https://d.example"), vec!["https://d.example", "/post/x"]);
+        assert_eq!(links("`[no](https://e.example)`\n\n    [no](https://f.example)"), Vec::<String>::new());
+        assert_eq!(links("[![img](https://c.example/i.png)](https://g.example)"), vec!["https://g.example"]);
+    }
+
+    #[test]
+    fn leaves_unclosed_dollars() {
+        assert_eq!(render("p", "$unclosed"), "<p>$unclosed</p>\n");
+        assert_eq!(render("p", "$$unclosed"), "<p>$$unclosed</p>\n");
+        assert_eq!(render("p", "From $5 to $10"), "<p>From $5 to $10</p>\n");
+        assert_eq!(render("p", "\\$x$"), "<p>$x$</p>\n");
+        assert_eq!(render("p", "$a\n\nb$"), "<p>$a</p>\n<p>b$</p>\n"); // Not across paragraphs.
+    }
+
+    #[test]
+    fn leaves_code_alone() {
+        assert_eq!(render("p", "a $x$ b `$y$` c $z$"), "<p>a <math><mi>x</mi></math> b <code>$y$</code> c <math><mi>z</mi></math></p>\n");
+        assert_eq!(render("p", "$a `b` c$"), "<p>$a <code>b</code> c$</p>\n"); // Not around code either.
+        assert_eq!(render("p", "```\n$x$\n```"), "<pre><code>$x$\n</code></pre>\n");
+    }
+
+    #[test]
+    fn renders_math_in_links() {
+        assert_eq!(render("p", "[$x$](https://a.example/$y$ \"$t$\")"), "<p><a href=\"https://a.example/$y$\" title=\"$t$\" rel=\"noopener noreferrer\"><math><mi>x</mi></math></a></p>\n");
+        assert_eq!(render("p", "![$x$](i.png)"), "<p><img src=\"i.png\" alt=\"$x$\"></p>\n");
+    }
+
+    #[test]
+    fn renders_many_dollars() {
+        let html = render("p", &"$a".repeat(MAX_CONTENT / 4));
+        assert_eq!(html.matches("<math>").count(), MAX_CONTENT / 8);
+        let html = render("p", &("$$".to_owned() + &"a $".repeat(MAX_CONTENT / 6)));
+        assert!(html.starts_with("<p>$$a $a $") && !html.contains("<math>"));
+    }
+
+    #[test]
+    fn dollars_before_multibyte_chars() {
+        assert_eq!(render("p", "$é"), "<p>$é</p>\n");
+        assert_eq!(render("p", "$é and ü$"), "<p><math><mrow><mi>é</mi><mi>a</mi><mi>n</mi><mi>d</mi><mi>ü</mi></mrow></math></p>\n");
+    }
+}
\ No newline at end of file
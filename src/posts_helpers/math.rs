@@ -0,0 +1,517 @@
+//! Converts TeX-like math (as written between `$`s in posts) to MathML, which browsers display with no JS.
+//!
+//! Covers what people usually type: scripts, fractions, roots, Greek letters, common operators & functions, accents, `\left…\right`, matrices and cases, `\text{…}`, and font commands. Unknown commands are shown as errors instead of failing the whole formula.
+
+
+
+#[derive(Clone, PartialEq)]
+enum Token {
+    Command(String), // `\frac`, or `\{` as `{`.
+    Text(String), // The raw argument of `\text{…}` and friends, whitespace included.
+    Open,
+    Close,
+    Sup,
+    Sub,
+    Amp,
+    Newline, // `\\`
+    Number(String),
+    Char(char),
+}
+
+/// Commands whose argument is kept as-is, rather than parsed as math.
+const TEXT_COMMANDS: &[&str] = &["text", "textrm", "textit", "textbf", "mbox", "operatorname"];
+
+/// How deeply nodes may nest (as in `{{{…}}}` or `\sqrt{\sqrt{…}}`), so that parsing can't overflow the stack.
+const MAX_DEPTH: usize = 64;
+
+fn tokenize(tex: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut chars = tex.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('\\') => tokens.push(Token::Newline),
+                Some(c) if c.is_ascii_alphabetic() => {
+                    let mut name = c.to_string();
+                    while let Some(&c) = chars.peek() {
+                        if !c.is_ascii_alphabetic() { break };
+                        name.push(c);
+                        chars.next();
+                    }
+                    if TEXT_COMMANDS.contains(&&name[..]) {
+                        while chars.peek().map_or(false, |c| c.is_whitespace()) { chars.next(); }
+                        if chars.peek() == Some(&'{') {
+                            chars.next();
+                            let mut depth = 0;
+                            let mut text = String::new();
+                            for c in chars.by_ref() {
+                                match c {
+                                    '{' => depth += 1,
+                                    '}' if depth == 0 => break,
+                                    '}' => depth -= 1,
+                                    _ => (),
+                                }
+                                text.push(c);
+                            }
+                            tokens.push(Token::Command(name));
+                            tokens.push(Token::Text(text));
+                            continue
+                        }
+                    }
+                    tokens.push(Token::Command(name));
+                },
+                Some(c) => tokens.push(Token::Command(c.to_string())),
+                None => (),
+            },
+            '{' => tokens.push(Token::Open),
+            '}' => tokens.push(Token::Close),
+            '^' => tokens.push(Token::Sup),
+            '_' => tokens.push(Token::Sub),
+            '&' => tokens.push(Token::Amp),
+            c if c.is_whitespace() => (),
+            c if c.is_ascii_digit() => {
+                let mut n = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if !c.is_ascii_digit() && c != '.' { break };
+                    n.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Number(n));
+            },
+            c => tokens.push(Token::Char(c)),
+        }
+    }
+    tokens
+}
+
+
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn mrow(nodes: Vec<String>) -> String {
+    if nodes.len() == 1 {
+        nodes.into_iter().next().unwrap()
+    } else {
+        format!("<mrow>{}</mrow>", nodes.concat())
+    }
+}
+
+fn mo(op: &str) -> String {
+    format!("<mo>{}</mo>", escape(op))
+}
+
+fn fence(delimiter: &str) -> String {
+    if delimiter.is_empty() { String::new() } else { format!("<mo fence=\"true\" stretchy=\"true\">{}</mo>", escape(delimiter)) }
+}
+
+fn greek(name: &str) -> Option<char> {
+    Some(match name {
+        "alpha" => 'α', "beta" => 'β', "gamma" => 'γ', "delta" => 'δ', "epsilon" => 'ϵ', "varepsilon" => 'ε',
+        "zeta" => 'ζ', "eta" => 'η', "theta" => 'θ', "vartheta" => 'ϑ', "iota" => 'ι', "kappa" => 'κ',
+        "lambda" => 'λ', "mu" => 'μ', "nu" => 'ν', "xi" => 'ξ', "pi" => 'π', "varpi" => 'ϖ', "rho" => 'ρ',
+        "varrho" => 'ϱ', "sigma" => 'σ', "varsigma" => 'ς', "tau" => 'τ', "upsilon" => 'υ', "phi" => 'ϕ',
+        "varphi" => 'φ', "chi" => 'χ', "psi" => 'ψ', "omega" => 'ω',
+        "Gamma" => 'Γ', "Delta" => 'Δ', "Theta" => 'Θ', "Lambda" => 'Λ', "Xi" => 'Ξ', "Pi" => 'Π',
+        "Sigma" => 'Σ', "Upsilon" => 'Υ', "Phi" => 'Φ', "Psi" => 'Ψ', "Omega" => 'Ω',
+        _ => return None,
+    })
+}
+
+/// Symbols that are identifiers rather than operators.
+fn letterlike(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "infty" => "∞", "emptyset" => "∅", "varnothing" => "∅", "partial" => "∂", "nabla" => "∇",
+        "ell" => "ℓ", "hbar" => "ℏ", "aleph" => "ℵ", "Re" => "ℜ", "Im" => "ℑ", "wp" => "℘",
+        "ldots" => "…", "dots" => "…", "cdots" => "⋯", "vdots" => "⋮", "ddots" => "⋱",
+        "prime" => "′", "angle" => "∠", "triangle" => "△", "top" => "⊤", "bot" => "⊥",
+        _ => return None,
+    })
+}
+
+fn operator(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "pm" => "±", "mp" => "∓", "times" => "×", "div" => "÷", "cdot" => "⋅", "ast" => "∗", "star" => "⋆",
+        "circ" => "∘", "bullet" => "∙", "oplus" => "⊕", "ominus" => "⊖", "otimes" => "⊗", "odot" => "⊙",
+        "cap" => "∩", "cup" => "∪", "setminus" => "∖", "wedge" => "∧", "land" => "∧", "vee" => "∨", "lor" => "∨",
+        "neg" => "¬", "lnot" => "¬", "forall" => "∀", "exists" => "∃", "nexists" => "∄",
+        "leq" => "≤", "le" => "≤", "geq" => "≥", "ge" => "≥", "neq" => "≠", "ne" => "≠", "ll" => "≪", "gg" => "≫",
+        "approx" => "≈", "sim" => "∼", "simeq" => "≃", "cong" => "≅", "equiv" => "≡", "propto" => "∝",
+        "in" => "∈", "notin" => "∉", "ni" => "∋", "subset" => "⊂", "supset" => "⊃", "subseteq" => "⊆",
+        "supseteq" => "⊇", "mid" => "∣", "parallel" => "∥", "perp" => "⊥",
+        "to" => "→", "rightarrow" => "→", "leftarrow" => "←", "gets" => "←", "leftrightarrow" => "↔",
+        "Rightarrow" => "⇒", "Leftarrow" => "⇐", "Leftrightarrow" => "⇔", "implies" => "⟹", "iff" => "⟺",
+        "mapsto" => "↦", "uparrow" => "↑", "downarrow" => "↓",
+        "langle" => "⟨", "rangle" => "⟩", "lfloor" => "⌊", "rfloor" => "⌋", "lceil" => "⌈", "rceil" => "⌉",
+        "vert" => "|", "Vert" => "‖", "|" => "‖", "{" => "{", "}" => "}", "lbrace" => "{", "rbrace" => "}",
+        "$" => "$", "%" => "%", "&" => "&", "#" => "#", "_" => "_",
+        "colon" => ":",
+        _ => return None,
+    })
+}
+
+/// Big operators, with their limits placed under/over in display mode.
+fn large_operator(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "sum" => "∑", "prod" => "∏", "coprod" => "∐", "bigcup" => "⋃", "bigcap" => "⋂",
+        "bigoplus" => "⨁", "bigotimes" => "⨂", "bigvee" => "⋁", "bigwedge" => "⋀",
+        _ => return None,
+    })
+}
+
+fn integral(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "int" => "∫", "iint" => "∬", "iiint" => "∭", "oint" => "∮",
+        _ => return None,
+    })
+}
+
+const FUNCTIONS: &[&str] = &["sin", "cos", "tan", "cot", "sec", "csc", "sinh", "cosh", "tanh", "coth", "arcsin", "arccos", "arctan", "log", "ln", "lg", "exp", "det", "dim", "ker", "deg", "arg", "gcd", "hom", "Pr", "bmod"];
+const FUNCTIONS_WITH_LIMITS: &[&str] = &["lim", "liminf", "limsup", "max", "min", "sup", "inf", "argmax", "argmin"];
+
+fn accent(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "hat" | "widehat" => "^", "tilde" | "widetilde" => "~", "bar" | "overline" => "‾", "vec" => "→",
+        "overrightarrow" => "→", "overleftarrow" => "←", "dot" => "˙", "ddot" => "¨", "acute" => "´",
+        "grave" => "`", "breve" => "˘", "check" => "ˇ",
+        _ => return None,
+    })
+}
+
+fn space(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "," | "thinspace" => "0.167em", ":" | ">" | "medspace" => "0.222em", ";" | "thickspace" => "0.278em",
+        " " => "0.25em", "quad" => "1em", "qquad" => "2em", "!" => "0em",
+        _ => return None,
+    })
+}
+
+/// Converts a Latin letter to its styled Unicode form (as fonts are not reliably controllable in MathML).
+fn styled(c: char, variant: &str) -> char {
+    if !c.is_ascii_alphabetic() { return c };
+    let (upper, lower, exceptions): (u32, u32, &[(char, char)]) = match variant {
+        "bold" => (0x1D400, 0x1D41A, &[]),
+        "double-struck" => (0x1D538, 0x1D552, &[('C', 'ℂ'), ('H', 'ℍ'), ('N', 'ℕ'), ('P', 'ℙ'), ('Q', 'ℚ'), ('R', 'ℝ'), ('Z', 'ℤ')]),
+        "script" => (0x1D49C, 0x1D4B6, &[('B', 'ℬ'), ('E', 'ℰ'), ('F', 'ℱ'), ('H', 'ℋ'), ('I', 'ℐ'), ('L', 'ℒ'), ('M', 'ℳ'), ('R', 'ℛ'), ('e', 'ℯ'), ('g', 'ℊ'), ('o', 'ℴ')]),
+        "fraktur" => (0x1D504, 0x1D51E, &[('C', 'ℭ'), ('H', 'ℌ'), ('I', 'ℑ'), ('R', 'ℜ'), ('Z', 'ℨ')]),
+        "sans-serif" => (0x1D5A0, 0x1D5BA, &[]),
+        "monospace" => (0x1D670, 0x1D68A, &[]),
+        _ => return c,
+    };
+    if let Some((_, e)) = exceptions.iter().find(|(from, _)| *from == c) { return *e };
+    let base = if c.is_ascii_uppercase() { upper + (c as u32 - 'A' as u32) } else { lower + (c as u32 - 'a' as u32) };
+    std::char::from_u32(base).unwrap_or(c)
+}
+
+fn font(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "mathrm" | "rm" => "normal", "mathbf" | "bf" | "boldsymbol" => "bold", "mathbb" => "double-struck",
+        "mathcal" | "mathscr" => "script", "mathfrak" => "fraktur", "mathsf" => "sans-serif", "mathtt" => "monospace",
+        "mathit" => "italic",
+        _ => return None,
+    })
+}
+
+
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    variant: Option<&'static str>, // The font of letters, from `\mathbf` and friends.
+    depth: usize, // How many `atom`s are being parsed, one within the other.
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+    fn is_command(&self, name: &str) -> bool {
+        matches!(self.peek(), Some(Token::Command(c)) if c == name)
+    }
+
+    /// Parses nodes until a `}`, `&`, `\\`, `\end`, `\right`, or the `closing` char, without consuming it.
+    fn row(&mut self, closing: Option<char>) -> Vec<String> {
+        let mut nodes = vec![];
+        loop {
+            match self.peek() {
+                None | Some(Token::Close) | Some(Token::Amp) | Some(Token::Newline) => break,
+                Some(Token::Command(c)) if c == "end" || c == "right" => break,
+                Some(Token::Char(c)) if Some(*c) == closing => break,
+                _ => (),
+            }
+            nodes.push(self.scripted());
+        }
+        nodes
+    }
+
+    /// `{…}`, as one node.
+    fn group(&mut self) -> String {
+        let nodes = self.row(None);
+        if self.peek() == Some(&Token::Close) { self.next(); }
+        mrow(nodes)
+    }
+
+    /// A command's argument: a group, or a single token (a single digit, for numbers, as in `\frac12`).
+    fn arg(&mut self) -> String {
+        match self.peek() {
+            Some(Token::Open) => { self.next(); self.group() },
+            Some(Token::Close) | None => "<mrow></mrow>".to_owned(),
+            Some(Token::Command(c)) if c == "end" || c == "right" => "<mrow></mrow>".to_owned(),
+            Some(Token::Number(n)) if n.len() > 1 => {
+                let (digit, rest) = n.split_at(1);
+                let digit = format!("<mn>{}</mn>", digit);
+                self.tokens[self.pos] = Token::Number(rest.to_owned());
+                digit
+            },
+            _ => self.atom(),
+        }
+    }
+
+    /// A node with its sub/superscripts, if any.
+    fn scripted(&mut self) -> String {
+        let (base, limits) = match self.peek() {
+            Some(Token::Sup) | Some(Token::Sub) => ("<mrow></mrow>".to_owned(), false),
+            Some(Token::Command(c)) => {
+                let limits = large_operator(c).is_some() || FUNCTIONS_WITH_LIMITS.contains(&&c[..]);
+                (self.atom(), limits)
+            },
+            _ => (self.atom(), false),
+        };
+        let (mut sub, mut sup) = (None, None);
+        loop {
+            match self.peek() {
+                Some(Token::Sub) if sub.is_none() => { self.next(); sub = Some(self.arg()) },
+                Some(Token::Sup) if sup.is_none() => { self.next(); sup = Some(self.arg()) },
+                Some(Token::Char('\'')) if sup.is_none() => {
+                    let mut primes = String::new();
+                    while self.peek() == Some(&Token::Char('\'')) { self.next(); primes.push('′'); }
+                    sup = Some(mo(&primes));
+                },
+                _ => break,
+            }
+        }
+        let (under, over, both) = if limits { ("munder", "mover", "munderover") } else { ("msub", "msup", "msubsup") };
+        match (sub, sup) {
+            (None, None) => base,
+            (Some(b), None) => format!("<{0}>{1}{2}</{0}>", under, base, b),
+            (None, Some(p)) => format!("<{0}>{1}{2}</{0}>", over, base, p),
+            (Some(b), Some(p)) => format!("<{0}>{1}{2}{3}</{0}>", both, base, b, p),
+        }
+    }
+
+    /// A single node, without scripts.
+    /// Every nesting goes through here, so past `MAX_DEPTH` the rest of the formula is given up on.
+    fn atom(&mut self) -> String {
+        if self.depth >= MAX_DEPTH {
+            self.pos = self.tokens.len();
+            return "<merror><mtext>Too deeply nested</mtext></merror>".to_owned()
+        }
+        self.depth += 1;
+        let node = self.atom_within();
+        self.depth -= 1;
+        node
+    }
+    fn atom_within(&mut self) -> String {
+        match self.next() {
+            None => String::new(),
+            Some(Token::Open) => self.group(),
+            Some(Token::Number(n)) => format!("<mn>{}</mn>", escape(&n)),
+            Some(Token::Char(c)) if c.is_alphabetic() => self.identifier(c),
+            Some(Token::Char('-')) => mo("−"),
+            Some(Token::Char('*')) => mo("∗"),
+            Some(Token::Char('\'')) => mo("′"),
+            Some(Token::Char(c)) => mo(&c.to_string()),
+            Some(Token::Text(t)) => format!("<mtext>{}</mtext>", escape(&t).replace(' ', "\u{a0}")),
+            Some(Token::Command(name)) => self.command(&name),
+            Some(Token::Close) | Some(Token::Sup) | Some(Token::Sub) | Some(Token::Amp) | Some(Token::Newline) => String::new(),
+        }
+    }
+
+    fn identifier(&self, c: char) -> String {
+        match self.variant {
+            Some("normal") => format!("<mi mathvariant=\"normal\">{}</mi>", escape(&c.to_string())),
+            Some(v) => format!("<mi>{}</mi>", escape(&styled(c, v).to_string())),
+            None => format!("<mi>{}</mi>", escape(&c.to_string())),
+        }
+    }
+
+    fn command(&mut self, name: &str) -> String {
+        if let Some(c) = greek(name) {
+            return if c.is_uppercase() { format!("<mi mathvariant=\"normal\">{}</mi>", c) } else { format!("<mi>{}</mi>", c) }
+        }
+        if let Some(s) = letterlike(name) { return format!("<mi>{}</mi>", s) }
+        if let Some(s) = operator(name) { return mo(s) }
+        if let Some(s) = large_operator(name) { return format!("<mo largeop=\"true\" movablelimits=\"true\">{}</mo>", s) }
+        if let Some(s) = integral(name) { return format!("<mo largeop=\"true\">{}</mo>", s) }
+        if FUNCTIONS.contains(&name) { return format!("<mi>{}</mi>", if name == "bmod" { "mod" } else { name }) }
+        if FUNCTIONS_WITH_LIMITS.contains(&name) {
+            let shown = match name { "liminf" => "lim inf", "limsup" => "lim sup", "argmax" => "arg max", "argmin" => "arg min", n => n };
+            return format!("<mo form=\"prefix\" movablelimits=\"true\">{}</mo>", shown)
+        }
+        if let Some(width) = space(name) { return format!("<mspace width=\"{}\"></mspace>", width) }
+        if let Some(a) = accent(name) {
+            let arg = self.arg();
+            return format!("<mover accent=\"true\">{}{}</mover>", arg, mo(a))
+        }
+        if let Some(v) = font(name) {
+            let old = std::mem::replace(&mut self.variant, Some(v));
+            let arg = self.arg();
+            self.variant = old;
+            return arg
+        }
+        match name {
+            "frac" | "dfrac" | "tfrac" => {
+                let (num, den) = (self.arg(), self.arg());
+                format!("<mfrac>{}{}</mfrac>", num, den)
+            },
+            "binom" => {
+                let (n, k) = (self.arg(), self.arg());
+                format!("<mrow><mo>(</mo><mfrac linethickness=\"0\">{}{}</mfrac><mo>)</mo></mrow>", n, k)
+            },
+            "sqrt" => {
+                if self.peek() == Some(&Token::Char('[')) {
+                    self.next();
+                    let index = mrow(self.row(Some(']')));
+                    self.next();
+                    let radicand = self.arg();
+                    format!("<mroot>{}{}</mroot>", radicand, index)
+                } else {
+                    format!("<msqrt>{}</msqrt>", self.arg())
+                }
+            },
+            "underline" => format!("<munder accentunder=\"true\">{}<mo>_</mo></munder>", self.arg()),
+            "text" | "textrm" | "textit" | "textbf" | "mbox" => match self.peek() {
+                Some(Token::Text(_)) => self.atom(),
+                _ => String::new(),
+            },
+            "operatorname" => match self.next() {
+                Some(Token::Text(t)) => format!("<mi>{}</mi>", escape(t.trim())),
+                _ => String::new(),
+            },
+            "left" => {
+                let open = self.delimiter();
+                let inner = self.row(None);
+                let close = if self.is_command("right") { self.next(); self.delimiter() } else { String::new() };
+                format!("<mrow>{}{}{}</mrow>", fence(&open), inner.concat(), fence(&close))
+            },
+            "middle" | "big" | "Big" | "bigg" | "Bigg" | "bigl" | "bigr" | "Bigl" | "Bigr" | "biggl" | "biggr" | "Biggl" | "Biggr" => {
+                fence(&self.delimiter())
+            },
+            "begin" => self.environment(),
+            "pmod" => {
+                let arg = self.arg();
+                format!("<mrow><mo>(</mo><mi>mod</mi><mspace width=\"0.333em\"></mspace>{}<mo>)</mo></mrow>", arg)
+            },
+            "mod" => "<mi>mod</mi>".to_owned(),
+            _ => format!("<merror><mtext>\\{}</mtext></merror>", escape(name)),
+        }
+    }
+
+    /// The delimiter after `\left`, `\right`, `\big` and such. `.` is none.
+    fn delimiter(&mut self) -> String {
+        match self.next() {
+            Some(Token::Char('.')) | None => String::new(),
+            Some(Token::Char(c)) => c.to_string(),
+            Some(Token::Command(name)) => operator(&name).unwrap_or("").to_owned(),
+            _ => String::new(),
+        }
+    }
+
+    /// A name in braces, such as `{pmatrix}`.
+    fn name(&mut self) -> String {
+        let mut name = String::new();
+        if self.peek() == Some(&Token::Open) {
+            self.next();
+            while let Some(t) = self.next() {
+                match t {
+                    Token::Char(c) => name.push(c),
+                    _ => break,
+                }
+            }
+        }
+        name
+    }
+
+    /// `\begin{…} … \end{…}`: matrices, cases, and aligned equations.
+    fn environment(&mut self) -> String {
+        let env = self.name();
+        if env == "array" { self.name(); } // Column spec.
+        let mut rows = vec![];
+        loop {
+            let mut cells = vec![];
+            loop {
+                cells.push(format!("<mtd>{}</mtd>", mrow(self.row(None))));
+                match self.peek() {
+                    Some(Token::Amp) => { self.next(); },
+                    _ => break,
+                }
+            }
+            rows.push(format!("<mtr>{}</mtr>", cells.concat()));
+            match self.peek() {
+                Some(Token::Newline) => { self.next(); },
+                Some(Token::Close) => { self.next(); }, // Stray `}`.
+                _ => break,
+            }
+        }
+        if self.is_command("end") { self.next(); self.name(); }
+        let (open, close, align) = match env.trim_end_matches('*') {
+            "pmatrix" => ("(", ")", ""),
+            "bmatrix" => ("[", "]", ""),
+            "Bmatrix" => ("{", "}", ""),
+            "vmatrix" => ("|", "|", ""),
+            "Vmatrix" => ("‖", "‖", ""),
+            "cases" => ("{", "", " columnalign=\"left\""),
+            "aligned" | "align" | "alignat" | "split" => ("", "", " columnalign=\"right left\""),
+            _ => ("", "", ""),
+        };
+        format!("<mrow>{}<mtable{}>{}</mtable>{}</mrow>", fence(open), align, rows.concat(), fence(close))
+    }
+}
+
+
+
+/// Converts TeX to MathML.
+///
+/// ```
+/// assert_eq!(to_mathml("x^2", false), "<math><msup><mi>x</mi><mn>2</mn></msup></math>");
+/// assert_eq!(to_mathml("\\frac{a}{b}", true), "<math display=\"block\"><mfrac><mi>a</mi><mi>b</mi></mfrac></math>");
+/// assert_eq!(to_mathml("\\alpha < \\beta", false), "<math><mrow><mi>α</mi><mo>&lt;</mo><mi>β</mi></mrow></math>");
+/// ```
+pub fn to_mathml(tex: &str, display: bool) -> String {
+    let mut parser = Parser { tokens: tokenize(tex), pos: 0, variant: None, depth: 0 };
+    let mut nodes = vec![];
+    while parser.peek().is_some() {
+        nodes.extend(parser.row(None));
+        if parser.peek().is_some() { parser.next(); } // A stray `}`, `&`, `\\`, `\end` or `\right`.
+    }
+    format!("<math{}>{}</math>", if display { " display=\"block\"" } else { "" }, mrow(nodes))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::to_mathml;
+
+    #[test]
+    fn converts_common_math() {
+        assert_eq!(to_mathml("x^2", false), "<math><msup><mi>x</mi><mn>2</mn></msup></math>");
+        assert_eq!(to_mathml("\\frac{a}{b}", true), "<math display=\"block\"><mfrac><mi>a</mi><mi>b</mi></mfrac></math>");
+        assert_eq!(to_mathml("\\alpha < \\beta", false), "<math><mrow><mi>α</mi><mo>&lt;</mo><mi>β</mi></mrow></math>");
+    }
+
+    #[test]
+    fn stops_at_deep_nesting() {
+        let braces = "{".repeat(20_000);
+        assert!(to_mathml(&braces, false).contains("<merror><mtext>Too deeply nested</mtext></merror>"));
+        let roots = "\\sqrt".repeat(20_000) + "x";
+        assert!(to_mathml(&roots, false).contains("<merror>"));
+        assert!(!to_mathml(&"{".repeat(10), false).contains("<merror>"));
+    }
+}
\ No newline at end of file
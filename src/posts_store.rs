@@ -2,15 +2,20 @@
 
 
 
+mod archive;
+mod events;
+mod repair;
+
 use crate::posts_api::Post;
 
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::collections::HashMap;
 
 use chrono::Datelike;
 use firebase_rs::Firebase;
 use serde::{Deserialize, Serialize};
-use serde_json::{from_str, to_string};
+use serde_json::{from_str, to_string, Value};
 
 
 
@@ -25,6 +30,7 @@ use serde_json::{from_str, to_string};
 /// Of data.
 pub struct Database {
     firebase: Firebase, // This doesn't need our synchronization.
+    listeners: events::Listeners,
 }
 
 
@@ -69,6 +75,28 @@ struct UserFirstPost {
 #[derive(Clone, Serialize, Deserialize)]
 struct Shortened {
     post_id: String,
+    /// If this URL was replaced by another, that one. (Old URLs keep working, as redirects.)
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    moved_to: String,
+}
+
+
+
+/// Why an author-chosen human-readable URL can't be used.
+pub enum UrlRejection {
+    Malformed,
+    Reserved,
+    Taken,
+}
+
+
+
+/// What a human-readable URL refers to.
+pub enum Resolved {
+    /// The ID of the post that has this URL.
+    Post(String),
+    /// The URL that replaced this one.
+    Moved(String),
 }
 
 
@@ -78,10 +106,15 @@ impl Database {
     pub fn new(fb: Firebase) -> Database {
         Database{
             firebase: fb,
+            listeners: events::Listeners::default(),
         }
     }
-    /// Reads many posts from the database at once.
+    /// Reads many posts from the database at once. Expired posts are treated as gone.
     pub fn read(&self, ids: Vec<&str>) -> Vec<Option<Post>> {
+        self.read_including_expired(ids).into_iter().map(|p| p.filter(|p| !p.is_expired())).collect()
+    }
+    /// Reads many posts from the database at once, even expired ones (which are only kept until they're erased).
+    pub fn read_including_expired(&self, ids: Vec<&str>) -> Vec<Option<Post>> {
         // `firebase_rs`'s `.get_async` API is really dumb. It's forcing Arc and Mutex on us.
         let mut values: Vec<Arc<Mutex<Option<Post>>>> = vec![];
         let mut handles: Vec<Option<std::thread::JoinHandle<()>>> = vec![];
@@ -108,16 +141,31 @@ impl Database {
     /// (Well, "atomic" is a word too strong for this: it was a nice thought, but firebase-rs has never heard of atomicity, and we don't care enough to implement transactions ourselves (https://stackoverflow.com/questions/23041800/firebase-transactions-via-rest-api).)
     /// (It first reads all, then writes all, each of these being atomic.)
     /// (And, `posts_api` reads/updates `children`, `rewarded_posts`, `created_post_ids` directly, with no regard for atomicity.)
+    /// 
+    /// New posts get a human-readable URL. If a post's URL is changed, the new one is claimed (suffixed if taken), and the old one redirects to it.
+    /// The tag index follows posts' tags, rewards and listing, the sitemap follows their URLs and listing, so do feeds, and live listeners hear of the changes (which is how rewards from `Post::reward` reach them).
     pub fn update<F>(&self, ids: Vec<&str>, action: F)
     where F: FnOnce(Vec<Option<Post>>) -> Vec<Option<Post>> {
-        let posts = self.read(ids);
-        let posts = action(posts);
+        // Expired posts are gone to `action`, but not to the indexes they're still in until they're erased.
+        let posts = self.read_including_expired(ids);
+        let old_posts: HashMap<String, Post> = posts.iter().flatten().map(|p| (p.id.clone(), p.clone())).collect();
+        let posts = action(posts.into_iter().map(|p| p.filter(|p| !p.is_expired())).collect());
         let mut handles: Vec<std::thread::JoinHandle<()>> = vec![];
         for maybe_post in posts {
             if let Some(mut post) = maybe_post {
+                let old_post = old_posts.get(&post.id);
+                Post::index_tags(self, old_post, &post);
+                let old_url = old_post.map(|p| &p.human_readable_url[..]).unwrap_or("");
                 if post.human_readable_url == "" {
-                    post.human_readable_url = to_url_part(&post.content);
+                    post.human_readable_url = to_url_part(&post.content, chrono::Utc::now().year());
                 }
+                if post.human_readable_url != old_url {
+                    post.human_readable_url = self.claim_url(&post.human_readable_url, &post.id);
+                    if old_url != "" { self.retire_url(old_url, &post.id, &post.human_readable_url) }
+                }
+                Post::index_sitemap(self, old_post, &post);
+                Post::index_author(self, old_post, &post);
+                Post::announce(self, old_post, &post); // Once it has a URL, to be linked to.
                 if post.access_hash != "" {
                     self.at(&["access_hash", &post.access_hash]).ok().map(|node| {
                         // To not overwrite, this needs a `".validate": "!data.exists()"` rule on `"access_hash"/"$hash"`.
@@ -127,12 +175,6 @@ impl Database {
                         b.map(|body| handles.push(node.update_async(body, |_| ())));
                     });
                 }
-                self.at(&["human_readable_url", &post.human_readable_url]).ok().map(|node| {
-                    let b = to_string(&Shortened {
-                        post_id: post.id.clone(),
-                    }).ok();
-                    b.map(|body| handles.push(node.update_async(body, |_| ())));
-                });
                 self.at(&["posts", &post.id]).ok().map(|node| {
                     let b = to_string(&post).ok();
                     b.map(|body| handles.push(node.set_async(body, |_| ())));
@@ -142,6 +184,19 @@ impl Database {
         for handle in handles { handle.join().unwrap(); }
     }
 
+    /// Sets how many change streams may be open at once, given how many threads the server has (see `events::Listeners::limit`).
+    pub fn limit_listeners(&self, server_threads: usize) {
+        self.listeners.limit(server_threads)
+    }
+    /// Streams the changes to a post (see `Post::announce`) as they're written; `None` if too many streams are open already.
+    pub fn listen(&self, post_id: &str) -> Option<impl iron::response::WriteBody> {
+        self.listeners.listen(post_id)
+    }
+    /// Tells a post's live listeners about a change.
+    pub fn emit(&self, post_id: &str, event: &str, data: &Value) {
+        self.listeners.emit(post_id, event, data)
+    }
+
     /// Looks up the access hash in the database, to get the first post ID that was made by it.
     /// Useful for retrieving a post's author (another post).
     /// Call the returned closure to get the result (the request is done async, so this is likely faster).
@@ -165,26 +220,166 @@ impl Database {
     pub fn login(&self, user: &str) -> Box<dyn FnOnce()->Option<String>> {
         return self.get_first_post(&crate::posts_api::access_token_hash(user))
     }
-    /// Converts a human-readable URL to the post ID, if present in the database.
+    /// Converts a human-readable URL to the post ID (or to the URL that replaced it), if present in the database.
     /// To get a post's URL, read `post.human_readable_url`: an empty string if not assigned.
     /// These URLs are auto-assigned, and will never collide with raw post IDs, nor with statically-served files (since these URLs are like `"2020_first_line_of_content"`).
-    pub fn lookup_url(&self, url: &str) -> Option<String> {
+    pub fn resolve_url(&self, url: &str) -> Option<Resolved> {
+        self.lookup_shortened(url).map(|s| {
+            if s.moved_to != "" { Resolved::Moved(s.moved_to) } else { Resolved::Post(s.post_id) }
+        })
+    }
+    fn lookup_shortened(&self, url: &str) -> Option<Shortened> {
         self.at(&["human_readable_url", url]).ok().map(|node| {
-            node.get().ok().map(|r| from_str::<Shortened>(&r.body).ok().map(|s| s.post_id)).flatten()
+            node.get().ok().map(|r| from_str::<Shortened>(&r.body).ok()).flatten()
         }).flatten()
     }
+    /// Checks whether an author can give this human-readable URL to a post (`post_id` is `None` for a new post).
+    /// It must be up to 80 lowercase ASCII letters, digits, `_` and `-`, and not be any of `reserved` (the site's own paths), a post ID, or another post's URL.
+    pub fn check_url(&self, url: &str, post_id: Option<&str>, reserved: &[String]) -> Result<(), UrlRejection> {
+        if url.is_empty() || url.len() > 80 || !url.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-') {
+            return Err(UrlRejection::Malformed)
+        }
+        if reserved.iter().any(|r| r == url) || uuid::Uuid::parse_str(url).is_ok() {
+            return Err(UrlRejection::Reserved)
+        }
+        match self.lookup_shortened(url) {
+            Some(s) if Some(&s.post_id[..]) != post_id => Err(UrlRejection::Taken),
+            _ => Ok(()),
+        }
+    }
+    /// Makes a human-readable URL point to a post, returning it. If it's taken by another post (even as a redirect), `_2`, `_3`, etc are tried instead.
+    /// (Not atomic: two posts claiming the same URL at the same moment may still collide.)
+    fn claim_url(&self, url: &str, post_id: &str) -> String {
+        let mut n = 1;
+        loop {
+            let candidate = if n == 1 { url.to_owned() } else { format!("{}_{}", url, n) };
+            match self.lookup_shortened(&candidate) {
+                Some(s) if s.post_id != post_id => n += 1,
+                _ => {
+                    let b = to_string(&Shortened{ post_id: post_id.to_owned(), moved_to: "".to_owned() }).ok();
+                    self.at(&["human_readable_url", &candidate]).ok().map(|node| b.map(|body| node.set(&body).ok()));
+                    return candidate
+                },
+            }
+        }
+    }
+    /// Gets the IDs of all posts. (Downloads the whole list, so it's for maintenance, not for pages.)
+    pub fn all_post_ids(&self) -> Vec<String> {
+        self.at(&["posts"]).ok().map(|node| {
+            let r = node.with_params().shallow(true).get().ok();
+            r.map(|r| from_str::<HashMap<String, bool>>(&r.body).ok()).flatten()
+        }).flatten().map(|m| m.into_keys().map(|id| if id == "_" { "".to_owned() } else { id }).collect()).unwrap_or_else(|| vec![])
+    }
+    /// Re-computes the human-readable URLs of all posts, in case `to_url_part` has changed. Old URLs redirect to the new ones.
+    /// The year in the URL stays the same, and URLs that authors chose are left alone.
+    pub fn reslug_all(&self) {
+        for ids in self.all_post_ids().chunks(32) {
+            let changed: Vec<String> = self.read(ids.iter().map(|s| &s[..]).collect()).into_iter().flatten().filter(|post| {
+                !post.custom_url && post.human_readable_url != reslug(post)
+            }).map(|post| post.id).collect();
+            for id in changed {
+                self.update(vec![&id], |mut posts| {
+                    match posts.remove(0) {
+                        Some(mut post) => {
+                            let url = reslug(&post);
+                            println!("{} → {}", post.human_readable_url, url);
+                            post.human_readable_url = url;
+                            vec![Some(post)]
+                        },
+                        None => vec![],
+                    }
+                });
+            }
+        }
+    }
+    /// Makes an old human-readable URL redirect to the new one.
+    fn retire_url(&self, old_url: &str, post_id: &str, new_url: &str) {
+        let b = to_string(&Shortened{ post_id: post_id.to_owned(), moved_to: new_url.to_owned() }).ok();
+        self.at(&["human_readable_url", old_url]).ok().map(|node| b.map(|body| node.set(&body).ok()));
+    }
+    /// Forgets every URL that leads to a post (its current one, and those that it replaced), except `keep`.
+    pub fn forget_urls(&self, post_id: &str, keep: &str) {
+        let response = self.at(&["human_readable_url"]).ok().map(|n| {
+            // Needs a `".indexOn": "post_id"` rule on `"human_readable_url"`.
+            let mut n = n.with_params();
+            let url = Arc::get_mut(&mut n.url).unwrap();
+            url.set_query(Some(&format!("orderBy=\"post_id\"&equalTo={}", serde_json::json!(post_id))));
+            n.get().ok()
+        }).flatten();
+        let urls = response.map(|r| from_str::<HashMap<String, Shortened>>(&r.body).ok()).flatten().unwrap_or_default();
+        for url in urls.keys().filter(|url| *url != keep) {
+            self.at(&["human_readable_url", url]).ok().map(|n| n.delete("").ok());
+        }
+    }
 }
 
 
 
-fn to_url_part(content: &str) -> String {
-    let year = chrono::Utc::now().year().to_string();
+/// What a post's human-readable URL would be now, keeping the year of the old one.
+fn reslug(post: &Post) -> String {
+    let year = post.human_readable_url.split('_').next().map(|y| y.parse::<i32>().ok()).flatten();
+    let url = to_url_part(&post.content, year.unwrap_or_else(|| chrono::Utc::now().year()));
+    // Keep the `_2` suffixes of URLs that had collided, if the URL is otherwise the same.
+    match post.human_readable_url.strip_prefix(&url) {
+        Some(suffix) if suffix.starts_with('_') && suffix[1..].parse::<u32>().is_ok() => post.human_readable_url.clone(),
+        _ => url,
+    }
+}
+
+/// Makes a human-readable URL out of a post's first line, such as `"2021_hello_world"`.
+/// Letters of any script are transliterated to ASCII, so that they don't all turn into `_`.
+/// 
+/// ```
+/// assert_eq!(to_url_part("# Hello, World!\nMore", 2021), "2021_hello_world");
+/// assert_eq!(to_url_part("Привет, мир", 2021), "2021_privet_mir");
+/// assert_eq!(to_url_part("Crème brûlée", 2021), "2021_creme_brulee");
+/// ```
+fn to_url_part(content: &str, year: i32) -> String {
     let simpler = match content.split_once('\n') {
         Some((line, _rest)) => line,
         None => content,
     };
-    let simpler = simpler.replace(|c:char| !c.is_ascii_alphanumeric(), "_");
-    let simpler = simpler.split('_').filter(|s| s.len() > 0).collect::<Vec<&str>>().join("_");
+    let simpler = deunicode::deunicode(simpler);
+    let simpler = simpler.split(|c:char| !c.is_ascii_alphanumeric()).filter(|s| s.len() > 0).collect::<Vec<&str>>().join("_");
     let simpler = simpler.to_ascii_lowercase();
-    year + "_" + if simpler.len() < 80 { &simpler } else { &simpler[..80] }
+    let simpler: String = simpler.chars().take(80).collect();
+    year.to_string() + "_" + simpler.trim_end_matches('_')
+}
+
+
+
+impl ToString for UrlRejection {
+    fn to_string(&self) -> String {
+        match self {
+            Self::Malformed => "A URL can only have up to 80 lowercase letters, digits, _ and -",
+            Self::Reserved => "This URL is reserved",
+            Self::Taken => "This URL is taken by another post",
+        }.to_string()
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transliterates_url_parts() {
+        assert_eq!(to_url_part("Привет, мир", 2021), "2021_privet_mir");
+        assert_eq!(to_url_part("Crème brûlée\nMore", 2021), "2021_creme_brulee");
+        assert_eq!(to_url_part("東京タワー", 2021), "2021_dong_jing_tawa");
+        assert_eq!(to_url_part("🦀 !!!", 2021), "2021_crab");
+        assert_eq!(to_url_part("", 2021), "2021_");
+        assert_eq!(to_url_part(&"я".repeat(100), 2021).len(), 5 + 80); // Transliterating makes it longer, but the cap is the same.
+    }
+
+    #[test]
+    fn reads_urls_from_before_redirects() {
+        let old: Shortened = from_str("{\"post_id\":\"a\"}").unwrap();
+        assert_eq!((&old.post_id[..], &old.moved_to[..]), ("a", ""));
+        assert_eq!(to_string(&old).unwrap(), "{\"post_id\":\"a\"}"); // Still written the same way, unless moved.
+        let moved = Shortened{ post_id: "a".to_owned(), moved_to: "2021_b".to_owned() };
+        assert_eq!(to_string(&moved).unwrap(), "{\"post_id\":\"a\",\"moved_to\":\"2021_b\"}");
+    }
 }
\ No newline at end of file
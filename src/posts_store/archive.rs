@@ -0,0 +1,208 @@
+//! Dumps the whole database into an archive, and loads it back, to back it up or to move it to another backend.
+//!
+//! The archive is JSON lines: a header, `{"format":"text_sharing_site","version":1}`, then one line per entry of each node, `{"node":"posts","key":"…","value":…}`.
+//! Keys are the IDs as the site knows them (the root post's is `""`), not as a backend stores them.
+//!
+//! A user can also take out just their own data (see `Database::takeout`).
+
+
+
+use super::Database;
+use crate::posts_api::access_token_hash;
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, Write};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, from_value, json, to_string, Value};
+
+
+
+/// Bumped whenever the meaning of the archive changes, so that old archives aren't misread.
+const VERSION: u32 = 1;
+
+/// Every node of the database, in the order that they're archived.
+pub(super) const NODES: [&str; 25] = ["posts", "children", "children_length", "user_reward", "saved", "following", "subscribers", "notifications", "unread_notifications", "webhooks", "webhook_deliveries", "ap_followers", "ap_actors", "ap_objects", "webmentions", "created_post_ids", "listed_post_ids", "drafts", "scheduled", "expiring", "tags", "tag_counts", "sitemap", "access_hash", "human_readable_url"];
+
+
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    format: String,
+    version: u32,
+}
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    node: String,
+    key: String,
+    value: Value,
+}
+#[derive(Deserialize)]
+struct Created {
+    post_id: String,
+}
+/// Just what an integrity check needs to know of a post.
+#[derive(Deserialize)]
+struct PostLinks {
+    parent_id: String,
+}
+
+
+
+impl Database {
+    /// Writes every node of the database to `out`, as an archive.
+    pub fn export(&self, mut out: impl Write) -> Result<(), String> {
+        let mut nodes = self.read_nodes()?;
+        let header = Header{ format: "text_sharing_site".to_owned(), version: VERSION };
+        writeln!(out, "{}", to_string(&header).unwrap()).map_err(|e| e.to_string())?;
+        for node in NODES {
+            let entries = nodes.remove(node).unwrap_or_default();
+            eprintln!("{}: {} entries", node, entries.len());
+            for (key, value) in entries {
+                let entry = Entry{ node: node.to_owned(), key, value };
+                writeln!(out, "{}", to_string(&entry).unwrap()).map_err(|e| e.to_string())?;
+            }
+        }
+        out.flush().map_err(|e| e.to_string())
+    }
+    /// Reads every node of the database whole, keyed by ID (with `"_"` turned back into `""`).
+    pub(super) fn read_nodes(&self) -> Result<HashMap<&'static str, BTreeMap<String, Value>>, String> {
+        let mut nodes = HashMap::new();
+        for node in NODES {
+            let r = self.at(&[node]).map_err(|e| format!("{:?}", e))?.get().map_err(|e| format!("Could not read `{}`: {:?}", node, e))?;
+            let entries = from_str::<Option<BTreeMap<String, Value>>>(&r.body).map_err(|e| format!("`{}` is not an object: {}", node, e))?.unwrap_or_default();
+            nodes.insert(node, entries.into_iter().map(|(key, value)| (if key == "_" { "".to_owned() } else { key }, value)).collect());
+        }
+        Ok(nodes)
+    }
+    /// Gathers everything that a user has put in: `{ format, version, user, posts, rewards_given, saved, following }`, where `user` is their first post's ID, `posts` are all the posts they created (as stored), `rewards_given` maps post IDs to the reward given to each, `saved` lists the IDs of the posts they saved, and `following` the access hashes of the users they follow.
+    /// (Posts' past revisions aren't kept, so only their current content is included.)
+    ///
+    /// Returns `None` if `user` is not a valid access token.
+    pub fn takeout(&self, user: &str) -> Option<Value> {
+        let first_post_id = self.login(user)()?;
+        let access_hash = access_token_hash(user);
+        let r = self.at(&["created_post_ids", &access_hash.replace(|c:char| !c.is_ascii_alphanumeric(), "_")]).ok().map(|n| n.get().ok()).flatten();
+        let created = r.map(|r| from_str::<Option<BTreeMap<String, Created>>>(&r.body).ok()).flatten().flatten().unwrap_or_default();
+        let ids: Vec<String> = created.into_values().map(|c| c.post_id).collect();
+        let posts: Vec<Value> = ids.chunks(32).flat_map(|ids| self.read(ids.iter().map(|s| &s[..]).collect())).flatten().map(|post| json!(post)).collect();
+        let r = self.at(&["user_reward", &first_post_id]).ok().map(|n| n.get().ok()).flatten();
+        let rewards = r.map(|r| from_str::<Option<BTreeMap<String, i8>>>(&r.body).ok()).flatten().flatten().unwrap_or_default();
+        let rewards: BTreeMap<String, i8> = rewards.into_iter().map(|(id, amount)| (if id == "_" { "".to_owned() } else { id }, amount)).collect();
+        let r = self.at(&["saved", &first_post_id]).ok().map(|n| n.get().ok()).flatten();
+        let saved = r.map(|r| from_str::<Option<BTreeMap<String, i64>>>(&r.body).ok()).flatten().flatten().unwrap_or_default();
+        let saved: Vec<String> = saved.into_keys().map(|id| if id == "_" { "".to_owned() } else { id }).collect();
+        let r = self.at(&["following", &first_post_id]).ok().map(|n| n.get().ok()).flatten();
+        let following: Vec<String> = r.map(|r| from_str::<Option<BTreeMap<String, String>>>(&r.body).ok()).flatten().flatten().unwrap_or_default().into_values().collect();
+        Some(json!({
+            "format": "text_sharing_site_takeout",
+            "version": VERSION,
+            "user": first_post_id,
+            "posts": posts,
+            "rewards_given": rewards,
+            "saved": saved,
+            "following": following,
+        }))
+    }
+    /// Loads an archive into the database, which must be empty.
+    ///
+    /// Nothing is written unless the whole archive is readable and consistent: every post's parent exists, every child is listed under its parent, and `children_length` matches `children`. Otherwise, returns every problem found.
+    /// Writes are one entry at a time, not one transaction, so they can fail halfway: then the database is only partly imported (and the next import would refuse it as not empty).
+    /// Entries that could not be written are returned as problems, after all others are written, along with the nodes that were written to, which must be deleted before trying again.
+    pub fn import(&self, input: impl BufRead) -> Result<(), Vec<String>> {
+        let mut lines = input.lines();
+        let header = lines.next().map(|l| l.ok()).flatten().map(|l| from_str::<Header>(&l).ok()).flatten();
+        match header {
+            Some(h) if h.format == "text_sharing_site" && h.version == VERSION => (),
+            Some(h) => return Err(vec![format!("Unsupported archive: {} version {}", h.format, h.version)]),
+            None => return Err(vec!["Not an archive: no header".to_owned()]),
+        }
+        let mut nodes: HashMap<&str, BTreeMap<String, Value>> = NODES.iter().map(|n| (*n, BTreeMap::new())).collect();
+        let mut problems = vec![];
+        for (i, line) in lines.enumerate() {
+            let entry = line.map_err(|e| e.to_string()).map(|l| from_str::<Entry>(&l).map_err(|e| e.to_string())).and_then(|r| r);
+            match entry {
+                Ok(entry) => match nodes.get_mut(&entry.node[..]) {
+                    Some(node) => { node.insert(entry.key, entry.value); },
+                    None => problems.push(format!("Line {}: unknown node `{}`", i+2, entry.node)),
+                },
+                Err(e) => problems.push(format!("Line {}: {}", i+2, e)),
+            }
+        }
+        problems.extend(check(&nodes));
+        if !problems.is_empty() { return Err(problems) };
+
+        let root = self.at(&[]).ok().map(|n| n.with_params().shallow(true).get().ok()).flatten();
+        match root {
+            Some(r) if r.body.trim() == "null" => (),
+            Some(_) => return Err(vec!["The database is not empty".to_owned()]),
+            None => return Err(vec!["Could not read the database".to_owned()]),
+        }
+        let failed: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(vec![]));
+        let mut written = vec![];
+        for node in NODES {
+            let entries: Vec<(&String, &Value)> = nodes[node].iter().collect();
+            if entries.is_empty() { continue };
+            eprintln!("Writing `{}`: {} entries", node, entries.len());
+            written.push(format!("`{}`", node));
+            for chunk in entries.chunks(32) {
+                let handles: Vec<_> = chunk.iter().map(|(key, value)| {
+                    let (failed, what) = (failed.clone(), format!("`{}` of `{}`", key, node));
+                    match self.at(&[node, key]) {
+                        Ok(n) => Some(n.set_async(value.to_string(), move |r| match r {
+                            Ok(r) if r.code / 100 == 2 => (),
+                            Ok(r) => failed.lock().unwrap().push(format!("Could not write {}: {}", what, r.code)),
+                            Err(e) => failed.lock().unwrap().push(format!("Could not write {}: {:?}", what, e)),
+                        })),
+                        Err(e) => { failed.lock().unwrap().push(format!("Could not write {}: {:?}", what, e)); None },
+                    }
+                }).collect();
+                for maybe_handle in handles { maybe_handle.map(|h| h.join().unwrap()); }
+            }
+        }
+        let mut failed = std::mem::take(&mut *failed.lock().unwrap());
+        if failed.is_empty() { return Ok(()) };
+        failed.push(format!("The database is partly imported: delete {} before trying again", written.join(", ")));
+        Err(failed)
+    }
+}
+
+
+
+/// Finds broken references between posts, and counts that don't match.
+fn check(nodes: &HashMap<&str, BTreeMap<String, Value>>) -> Vec<String> {
+    let mut problems = vec![];
+    let posts: HashMap<&str, PostLinks> = nodes["posts"].iter().filter_map(|(id, v)| {
+        match from_value::<PostLinks>(v.clone()) {
+            Ok(links) => Some((&id[..], links)),
+            Err(e) => { problems.push(format!("Post `{}`: {}", id, e)); None },
+        }
+    }).collect();
+    for (id, links) in &posts {
+        if !posts.contains_key(&links.parent_id[..]) {
+            problems.push(format!("Post `{}`: parent `{}` does not exist", id, links.parent_id));
+        }
+    }
+    let empty = serde_json::Map::new();
+    for (parent_id, children) in &nodes["children"] {
+        if !posts.contains_key(&parent_id[..]) {
+            problems.push(format!("Children of `{}`: the post does not exist", parent_id));
+        }
+        for child_id in children.as_object().unwrap_or(&empty).keys() {
+            match posts.get(&child_id[..]) {
+                Some(child) if &child.parent_id == parent_id => (),
+                Some(child) => problems.push(format!("Children of `{}`: `{}` belongs to `{}`", parent_id, child_id, child.parent_id)),
+                None => problems.push(format!("Children of `{}`: `{}` does not exist", parent_id, child_id)),
+            }
+        }
+    }
+    for id in posts.keys() {
+        let listed = nodes["children"].get(*id).map(|c| c.as_object().map(|o| o.len())).flatten().unwrap_or(0) as i64;
+        let counted = nodes["children_length"].get(*id).map(|n| n.as_i64()).flatten().unwrap_or(0);
+        if listed != counted {
+            problems.push(format!("Post `{}`: `children_length` is {}, but {} children are listed", id, counted, listed));
+        }
+    }
+    problems
+}
\ No newline at end of file
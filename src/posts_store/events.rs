@@ -0,0 +1,134 @@
+//! Tells live listeners about changes to posts as they're written, as Server-Sent Events.
+//!
+//! Listeners are only kept in memory, so they hear of what this server process writes, not of what others do.
+
+
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+use iron::response::WriteBody;
+use serde_json::Value;
+
+
+
+/// How long a stream stays open (then browsers' `EventSource` reconnects by itself).
+const LIFETIME: Duration = Duration::from_secs(600);
+
+/// How often to send a comment when there's nothing to say, so that closed connections are noticed.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+
+
+/// Each post's listeners, by the number of their stream.
+type Senders = Arc<Mutex<HashMap<String, Vec<(usize, Sender<String>)>>>>;
+
+/// Who is listening to what post, and how many streams may be open at once.
+#[derive(Default)]
+pub struct Listeners {
+    senders: Senders,
+    /// How many streams are open right now.
+    open: Arc<AtomicUsize>,
+    /// How many streams may be open at once.
+    limit: AtomicUsize,
+    /// The number of the next stream.
+    next: AtomicUsize,
+}
+
+/// The body of a `text/event-stream` response, that ends after `LIFETIME`, or when the listener leaves.
+/// Once it's dropped, it no longer counts as open, nor among the listeners of its post.
+pub struct Stream {
+    events: Receiver<String>,
+    post_id: String,
+    number: usize,
+    senders: Senders,
+    open: Arc<AtomicUsize>,
+}
+
+
+
+impl Listeners {
+    /// Sets how many streams may be open at once, given how many threads the server has.
+    /// Each stream takes up one of those threads for up to `LIFETIME`, so by default only an eighth of them can (at least 1); `MAX_EVENT_STREAMS` can set it otherwise, but never to more than half of them, so that pages are still served.
+    pub fn limit(&self, server_threads: usize) {
+        let max = std::env::var("MAX_EVENT_STREAMS").ok().map(|s| s.parse::<usize>().ok()).flatten().unwrap_or(server_threads / 8);
+        self.limit.store(max.min(server_threads / 2).max(1), Ordering::SeqCst);
+    }
+    /// Starts listening to a post; `None` if too many streams are open already (see `limit`, and none until it's called).
+    pub fn listen(&self, post_id: &str) -> Option<Stream> {
+        if self.open.fetch_add(1, Ordering::SeqCst) >= self.limit.load(Ordering::SeqCst) {
+            self.open.fetch_sub(1, Ordering::SeqCst);
+            return None
+        }
+        let (sender, events) = channel();
+        let number = self.next.fetch_add(1, Ordering::SeqCst);
+        self.senders.lock().unwrap().entry(post_id.to_owned()).or_default().push((number, sender));
+        Some(Stream{ events, post_id: post_id.to_owned(), number, senders: self.senders.clone(), open: self.open.clone() })
+    }
+    /// Sends an event to all listeners of a post, forgetting those that left.
+    pub fn emit(&self, post_id: &str, event: &str, data: &Value) {
+        let mut listeners = self.senders.lock().unwrap();
+        if let Some(senders) = listeners.get_mut(post_id) {
+            let message = format!("event: {}\ndata: {}\n\n", event, data);
+            senders.retain(|(_, s)| s.send(message.clone()).is_ok());
+            if senders.is_empty() { listeners.remove(post_id); }
+        }
+    }
+}
+
+impl WriteBody for Stream {
+    fn write_body(&mut self, res: &mut dyn Write) -> io::Result<()> {
+        let until = Instant::now() + LIFETIME;
+        res.write_all(b"retry: 5000\n\n")?;
+        res.flush()?;
+        while Instant::now() < until {
+            match self.events.recv_timeout(KEEP_ALIVE) {
+                Ok(message) => res.write_all(message.as_bytes())?,
+                Err(RecvTimeoutError::Timeout) => res.write_all(b": keep-alive\n\n")?,
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            res.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+        let mut listeners = self.senders.lock().unwrap();
+        if let Some(senders) = listeners.get_mut(&self.post_id) {
+            senders.retain(|(number, _)| *number != self.number);
+            if senders.is_empty() { listeners.remove(&self.post_id); }
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forgets_streams_that_end() {
+        let listeners = Listeners::default();
+        listeners.limit(16); // 2 streams.
+        let a = listeners.listen("p").unwrap();
+        let b = listeners.listen("q").unwrap();
+        assert!(listeners.listen("p").is_none());
+        drop(a);
+        assert!(listeners.senders.lock().unwrap().get("p").is_none());
+        let c = listeners.listen("p").unwrap();
+        listeners.emit("p", "edit", &Value::Null);
+        assert_eq!(c.events.try_recv().unwrap(), "event: edit\ndata: null\n\n");
+        assert!(b.events.try_recv().is_err());
+        drop((b, c));
+        assert!(listeners.senders.lock().unwrap().is_empty());
+        assert_eq!(listeners.open.load(Ordering::SeqCst), 0);
+    }
+}
\ No newline at end of file
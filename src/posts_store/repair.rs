@@ -0,0 +1,369 @@
+//! Finds where the database contradicts itself, and fixes it.
+//!
+//! `Post::new` and `Post::reward` write several nodes one after another, so an interrupted request leaves counts and indexes out of sync with the posts.
+
+
+
+use super::{Database, Shortened};
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde_json::{json, Value};
+
+
+
+/// A write that makes the database agree with itself.
+enum Fix {
+    Set(Vec<String>, Value),
+    Delete(Vec<String>),
+}
+
+/// Just what a consistency check needs to know of a post.
+struct Facts<'a> {
+    parent_id: &'a str,
+    reward: i64,
+    gave_reward: i64,
+    human_readable_url: &'a str,
+    listed: bool, // Drafts, unlisted and private posts aren't among their parents' children.
+    mapped: bool, // Nor in the sitemap, along with posts that expire.
+    access_hash: &'a str,
+    burn_after_reading: bool,
+    created: i64,
+    tags: Vec<&'a str>,
+}
+
+
+
+impl Database {
+    /// Walks the whole database, and prints everything that's inconsistent: counts, rewards, orphaned posts, and index entries that point nowhere.
+    /// With `fix`, also fixes what it can (orphaned posts are only reported, since there's no telling where they belong).
+    ///
+    /// Returns how many problems were found.
+    pub fn check_consistency(&self, fix: bool) -> Result<usize, String> {
+        let nodes = self.read_nodes()?;
+        let problems = find_problems(&nodes);
+        for (problem, maybe_fix) in &problems {
+            println!("{}", problem);
+            if !fix { continue };
+            let r = match maybe_fix {
+                Some(Fix::Set(path, value)) => self.at(&path.iter().map(|s| &s[..]).collect::<Vec<_>>()).ok().map(|n| n.set(&value.to_string()).ok()).flatten(),
+                Some(Fix::Delete(path)) => self.at(&path.iter().map(|s| &s[..]).collect::<Vec<_>>()).ok().map(|n| n.delete("").ok()).flatten(),
+                None => continue,
+            };
+            match r {
+                Some(r) if r.code / 100 == 2 => println!("  Fixed."),
+                _ => println!("  Could not fix."),
+            }
+        }
+        Ok(problems.len())
+    }
+}
+
+
+
+/// Compares every node to what the posts and `user_reward` (the sources of truth) say it should be.
+fn find_problems(nodes: &HashMap<&'static str, BTreeMap<String, Value>>) -> Vec<(String, Option<Fix>)> {
+    let mut problems = vec![];
+    let path = |parts: &[&str]| parts.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+    let id = |key: &str| if key == "_" { String::new() } else { key.to_owned() };
+
+    let posts: BTreeMap<&str, Facts> = nodes["posts"].iter().map(|(id, post)| (&id[..], Facts{
+        parent_id: post["parent_id"].as_str().unwrap_or(""),
+        reward: post["reward"].as_i64().unwrap_or(0),
+        gave_reward: post["gave_reward"].as_i64().unwrap_or(0),
+        human_readable_url: post["human_readable_url"].as_str().unwrap_or(""),
+        listed: !post["draft"].as_bool().unwrap_or(false) && post["visibility"].as_str().unwrap_or("public") == "public",
+        mapped: !post["draft"].as_bool().unwrap_or(false) && post["visibility"].as_str().unwrap_or("public") == "public" && post["expires_at"].as_i64().unwrap_or(0) == 0 && !post["burn_after_reading"].as_bool().unwrap_or(false),
+        access_hash: post["access_hash"].as_str().unwrap_or(""),
+        burn_after_reading: post["burn_after_reading"].as_bool().unwrap_or(false),
+        created: -post["reverse_date_created"].as_i64().unwrap_or(0),
+        tags: post["tags"].as_array().map(|t| t.iter().filter_map(|t| t.as_str()).collect()).unwrap_or_default(),
+    })).collect();
+
+    // Rewards: what each post got, and what each user gave.
+    let mut rewards: HashMap<&str, i64> = HashMap::new();
+    let mut given: HashMap<&str, i64> = HashMap::new();
+    for (user_id, rewarded) in &nodes["user_reward"] {
+        if !posts.contains_key(&user_id[..]) {
+            problems.push((format!("Rewards from `{}`: the user does not exist", user_id), Some(Fix::Delete(path(&["user_reward", user_id])))));
+            continue
+        }
+        for (post_id, amount) in entries(rewarded) {
+            let post_id = id(post_id);
+            let amount = amount.as_i64().unwrap_or(0);
+            match posts.get_key_value(&post_id[..]) {
+                Some((post_id, _)) => {
+                    *rewards.entry(post_id).or_insert(0) += amount;
+                    if amount != -100 { *given.entry(&user_id[..]).or_insert(0) += amount };
+                },
+                None => problems.push((format!("Reward from `{}`: post `{}` does not exist", user_id, post_id), Some(Fix::Delete(path(&["user_reward", user_id, &post_id]))))),
+            }
+        }
+    }
+    for (post_id, post) in &posts {
+        let reward = rewards.get(post_id).copied().unwrap_or(0);
+        if post.reward != reward {
+            problems.push((format!("Post `{}`: `reward` is {}, but rewards add up to {}", post_id, post.reward, reward), Some(Fix::Set(path(&["posts", post_id, "reward"]), json!(reward)))));
+        }
+        let gave = given.get(post_id).copied().unwrap_or(0);
+        if post.gave_reward != gave {
+            problems.push((format!("User `{}`: `gave_reward` is {}, but given rewards add up to {}", post_id, post.gave_reward, gave), Some(Fix::Set(path(&["posts", post_id, "gave_reward"]), json!(gave)))));
+        }
+    }
+
+    // Children: every listed post is under its parent, by its reward.
+    let mut lengths: HashMap<&str, i64> = HashMap::new();
+    for (post_id, post) in &posts {
+        if post_id == &post.parent_id { continue } // The root.
+        if !post.listed { continue }
+        if !posts.contains_key(post.parent_id) {
+            problems.push((format!("Post `{}`: orphaned, parent `{}` does not exist", post_id, post.parent_id), None));
+            continue
+        }
+        *lengths.entry(post.parent_id).or_insert(0) += 1;
+        let order = -rewards.get(post_id).copied().unwrap_or(0);
+        let listed = nodes["children"].get(post.parent_id).map(|c| c.get(post_id).map(|v| v.as_i64())).flatten().flatten();
+        if listed != Some(order) {
+            problems.push((format!("Post `{}`: listed under its parent as {:?}, instead of {}", post_id, listed, order), Some(Fix::Set(path(&["children", post.parent_id, post_id]), json!(order)))));
+        }
+    }
+    for (parent_id, children) in &nodes["children"] {
+        if !posts.contains_key(&parent_id[..]) {
+            problems.push((format!("Children of `{}`: the post does not exist", parent_id), Some(Fix::Delete(path(&["children", parent_id])))));
+            continue
+        }
+        for (child_id, _) in entries(children) {
+            match posts.get(&child_id[..]) {
+                Some(child) if !child.listed => problems.push((format!("Children of `{}`: `{}` is a draft, unlisted or private", parent_id, child_id), Some(Fix::Delete(path(&["children", parent_id, child_id]))))),
+                Some(child) if child.parent_id == parent_id => (),
+                Some(child) => problems.push((format!("Children of `{}`: `{}` belongs to `{}`", parent_id, child_id, child.parent_id), Some(Fix::Delete(path(&["children", parent_id, child_id]))))),
+                None => problems.push((format!("Children of `{}`: `{}` does not exist", parent_id, child_id), Some(Fix::Delete(path(&["children", parent_id, child_id]))))),
+            }
+        }
+    }
+    for post_id in posts.keys() {
+        let length = lengths.get(post_id).copied().unwrap_or(0);
+        let counted = nodes["children_length"].get(*post_id).map(|n| n.as_i64()).flatten().unwrap_or(0);
+        if counted != length {
+            problems.push((format!("Post `{}`: `children_length` is {}, but it has {} children", post_id, counted, length), Some(Fix::Set(path(&["children_length", post_id]), json!(length)))));
+        }
+    }
+    for post_id in nodes["children_length"].keys() {
+        if !posts.contains_key(&post_id[..]) {
+            problems.push((format!("`children_length` of `{}`: the post does not exist", post_id), Some(Fix::Delete(path(&["children_length", post_id])))));
+        }
+    }
+
+    // Indexes.
+    for (url, shortened) in &nodes["human_readable_url"] {
+        let post_id = shortened["post_id"].as_str().unwrap_or("");
+        if !posts.contains_key(post_id) {
+            problems.push((format!("URL `{}`: post `{}` does not exist", url, post_id), Some(Fix::Delete(path(&["human_readable_url", url])))));
+        }
+    }
+    for (post_id, post) in &posts {
+        let url = post.human_readable_url;
+        if url == "" { continue };
+        let indexed = nodes["human_readable_url"].get(url).map(|s| s["post_id"].as_str()).flatten();
+        if indexed != Some(post_id) {
+            let shortened = Shortened{ post_id: post_id.to_string(), moved_to: String::new() };
+            problems.push((format!("Post `{}`: its URL `{}` leads to {:?}", post_id, url, indexed), Some(Fix::Set(path(&["human_readable_url", url]), serde_json::to_value(&shortened).unwrap()))));
+        }
+    }
+    for post_id in nodes["sitemap"].keys() {
+        if !posts.get(&post_id[..]).map(|p| p.mapped).unwrap_or(false) {
+            problems.push((format!("Sitemap: post `{}` does not exist, or is not listed", post_id), Some(Fix::Delete(path(&["sitemap", post_id])))));
+        }
+    }
+    for (post_id, post) in &posts {
+        if !post.mapped { continue };
+        let url = format!("/post/{}", post.human_readable_url);
+        let entry = nodes["sitemap"].get(*post_id);
+        if entry.map(|e| e["url"].as_str()).flatten() != Some(&url[..]) {
+            let modified = entry.map(|e| e["modified"].as_i64()).flatten().unwrap_or(post.created);
+            problems.push((format!("Post `{}`: not in the sitemap as `{}`", post_id, url), Some(Fix::Set(path(&["sitemap", post_id]), json!({ "url": url, "modified": modified })))));
+        }
+    }
+    // Tags: every listed post is under each of its tags, by its reward and date, and each tag counts them.
+    let mut counts: BTreeMap<&str, i64> = BTreeMap::new();
+    for (post_id, post) in &posts {
+        if !post.listed { continue };
+        let entry = json!({ "reward": -rewards.get(post_id).copied().unwrap_or(0), "date": -post.created });
+        for tag in &post.tags {
+            *counts.entry(tag).or_insert(0) += 1;
+            let indexed = nodes["tags"].get(*tag).map(|t| t.get(post_id)).flatten();
+            if indexed != Some(&entry) {
+                problems.push((format!("Post `{}`: tagged `{}` as {}, instead of {}", post_id, tag, indexed.unwrap_or(&Value::Null), entry), Some(Fix::Set(path(&["tags", tag, post_id]), entry.clone()))));
+            }
+        }
+    }
+    for (tag, tagged) in &nodes["tags"] {
+        for (post_id, _) in entries(tagged) {
+            match posts.get(&post_id[..]) {
+                Some(post) if post.listed && post.tags.contains(&&tag[..]) => (),
+                _ => problems.push((format!("Tag `{}`: `{}` does not exist, or is not listed or tagged so", tag, post_id), Some(Fix::Delete(path(&["tags", tag, post_id]))))),
+            }
+        }
+    }
+    for tag in counts.keys().copied().chain(nodes["tag_counts"].keys().map(|t| &t[..])).collect::<std::collections::BTreeSet<&str>>() {
+        let count = counts.get(tag).copied().unwrap_or(0);
+        let counted = nodes["tag_counts"].get(tag).map(|n| n.as_i64()).flatten().unwrap_or(0);
+        if counted != count {
+            problems.push((format!("Tag `{}`: `tag_counts` is {}, but {} posts have it", tag, counted, count), Some(Fix::Set(path(&["tag_counts", tag]), json!(count)))));
+        }
+    }
+    // Each author's listed posts, as they're keyed by `access_hash` with its symbols replaced.
+    let author_key = |hash: &str| hash.replace(|c:char| !c.is_ascii_alphanumeric(), "_");
+    for (key, listed) in &nodes["listed_post_ids"] {
+        for (post_id, _) in entries(listed) {
+            let post_id = id(post_id);
+            match posts.get(&post_id[..]) {
+                Some(post) if post.listed && !post.burn_after_reading && author_key(post.access_hash) == *key => (),
+                _ => problems.push((format!("Listed posts of `{}`: `{}` does not exist, or is not theirs or listed", key, post_id), Some(Fix::Delete(path(&["listed_post_ids", key, &post_id]))))),
+            }
+        }
+    }
+    for (post_id, post) in &posts {
+        if !post.listed || post.burn_after_reading || post.access_hash == "" { continue };
+        let key = author_key(post.access_hash);
+        let indexed = nodes["listed_post_ids"].get(&key).map(|l| l.get(if post_id.is_empty() { "_" } else { post_id }).map(|v| v.as_i64())).flatten().flatten();
+        if indexed != Some(-post.created) {
+            problems.push((format!("Post `{}`: listed among its author's posts as {:?}, instead of {}", post_id, indexed, -post.created), Some(Fix::Set(path(&["listed_post_ids", &key, post_id]), json!(-post.created)))));
+        }
+    }
+    for (user_id, saved) in &nodes["saved"] {
+        if !posts.contains_key(&user_id[..]) {
+            problems.push((format!("Saved posts of `{}`: the user does not exist", user_id), Some(Fix::Delete(path(&["saved", user_id])))));
+            continue
+        }
+        for (post_id, _) in entries(saved) {
+            let post_id = id(post_id);
+            if !posts.contains_key(&post_id[..]) {
+                problems.push((format!("Saved by `{}`: post `{}` does not exist", user_id, post_id), Some(Fix::Delete(path(&["saved", user_id, &post_id])))));
+            }
+        }
+    }
+    for (user_id, following) in &nodes["following"] {
+        if !posts.contains_key(&user_id[..]) {
+            problems.push((format!("Follows of `{}`: the user does not exist", user_id), Some(Fix::Delete(path(&["following", user_id])))));
+            continue
+        }
+        for (key, hash) in entries(following) {
+            let hash = hash.as_str().unwrap_or("");
+            if !nodes["access_hash"].contains_key(hash) {
+                problems.push((format!("Followed by `{}`: user `{}` does not exist", user_id, hash), Some(Fix::Delete(path(&["following", user_id, key])))));
+            }
+        }
+    }
+    for (post_id, subscribers) in &nodes["subscribers"] {
+        if !posts.contains_key(&post_id[..]) {
+            problems.push((format!("Subscribers of `{}`: the post does not exist", post_id), Some(Fix::Delete(path(&["subscribers", post_id])))));
+            continue
+        }
+        for (user_id, _) in entries(subscribers) {
+            if !posts.contains_key(&user_id[..]) {
+                problems.push((format!("Subscribers of `{}`: user `{}` does not exist", post_id, user_id), Some(Fix::Delete(path(&["subscribers", post_id, user_id])))));
+            }
+        }
+    }
+    for node in ["notifications", "unread_notifications"] {
+        for user_id in nodes[node].keys() {
+            if !posts.contains_key(&user_id[..]) {
+                problems.push((format!("`{}` of `{}`: the user does not exist", node, user_id), Some(Fix::Delete(path(&[node, user_id])))));
+            }
+        }
+    }
+    for node in ["webhooks", "webhook_deliveries"] {
+        for post_id in nodes[node].keys() {
+            if !posts.contains_key(&post_id[..]) {
+                problems.push((format!("`{}` of `{}`: the post does not exist", node, post_id), Some(Fix::Delete(path(&[node, post_id])))));
+            }
+        }
+    }
+    for post_id in nodes["webmentions"].keys() {
+        if !posts.contains_key(&post_id[..]) {
+            problems.push((format!("Webmentions of `{}`: the post does not exist", post_id), Some(Fix::Delete(path(&["webmentions", post_id])))));
+        }
+    }
+    for post_id in nodes["ap_objects"].keys() {
+        if !posts.contains_key(&post_id[..]) {
+            problems.push((format!("Remote object of `{}`: the post does not exist", post_id), Some(Fix::Delete(path(&["ap_objects", post_id])))));
+        }
+    }
+    for node in ["ap_followers", "ap_actors"] {
+        for hash in nodes[node].keys() {
+            if !nodes["access_hash"].contains_key(hash) {
+                problems.push((format!("`{}` of `{}`: the user does not exist", node, hash), Some(Fix::Delete(path(&[node, hash])))));
+            }
+        }
+    }
+    for (hash, first_post) in &nodes["access_hash"] {
+        let post_id = first_post["first_post_id"].as_str().unwrap_or("");
+        if !posts.contains_key(post_id) {
+            problems.push((format!("User `{}`: first post `{}` does not exist", hash, post_id), Some(Fix::Delete(path(&["access_hash", hash])))));
+        }
+    }
+    for (hash, created) in &nodes["created_post_ids"] {
+        for (key, created) in entries(created) {
+            let post_id = created["post_id"].as_str().unwrap_or("");
+            if !posts.contains_key(post_id) {
+                problems.push((format!("Posts created by `{}`: `{}` does not exist", hash, post_id), Some(Fix::Delete(path(&["created_post_ids", hash, key])))));
+            }
+        }
+    }
+    problems
+}
+
+
+
+/// The entries of a JSON object (none if it's something else).
+fn entries(v: &Value) -> impl Iterator<Item=(&String, &Value)> {
+    v.as_object().into_iter().flatten()
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::archive::NODES;
+
+    /// Every node, empty unless it's in `given`.
+    fn nodes(given: Value) -> HashMap<&'static str, BTreeMap<String, Value>> {
+        NODES.iter().map(|&node| (node, serde_json::from_value(given[node].clone()).unwrap_or_default())).collect()
+    }
+
+    /// The problems found with tags, in order.
+    fn tag_problems(given: Value) -> Vec<String> {
+        let mut problems: Vec<String> = find_problems(&nodes(given)).into_iter().map(|(p, _)| p).filter(|p| p.starts_with("Tag ") || p.contains(": tagged ")).collect();
+        problems.sort();
+        problems
+    }
+
+    #[test]
+    fn finds_tags_out_of_step() {
+        let posts = json!({
+            "": { "parent_id": "" },
+            "a": { "parent_id": "", "tags": ["rust", "web"], "reverse_date_created": -5 },
+            "b": { "parent_id": "", "tags": ["rust"], "visibility": "unlisted", "reverse_date_created": -6 },
+        });
+        assert_eq!(tag_problems(json!({
+            "posts": posts,
+            "tags": { "rust": { "a": { "reward": 0, "date": -5 } }, "web": { "a": { "reward": 0, "date": -5 } } },
+            "tag_counts": { "rust": 1, "web": 1 },
+        })), Vec::<String>::new());
+        assert_eq!(tag_problems(json!({
+            "posts": posts,
+            "tags": { "rust": { "a": { "reward": 3, "date": -5 }, "b": { "reward": 0, "date": -6 } }, "old": { "c": { "reward": 0, "date": -7 } } },
+            "tag_counts": { "rust": 2, "old": 1 },
+        })), vec![
+            "Post `a`: tagged `rust` as {\"date\":-5,\"reward\":3}, instead of {\"date\":-5,\"reward\":0}",
+            "Post `a`: tagged `web` as null, instead of {\"date\":-5,\"reward\":0}",
+            "Tag `old`: `c` does not exist, or is not listed or tagged so",
+            "Tag `old`: `tag_counts` is 1, but 0 posts have it",
+            "Tag `rust`: `b` does not exist, or is not listed or tagged so",
+            "Tag `rust`: `tag_counts` is 2, but 1 posts have it",
+            "Tag `web`: `tag_counts` is 0, but 1 posts have it",
+        ]);
+    }
+}
\ No newline at end of file
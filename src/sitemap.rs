@@ -0,0 +1,107 @@
+//! Tells crawlers what there is to crawl: `robots.txt`, and `sitemap.xml` (https://www.sitemaps.org/protocol.html) of every listed post.
+//!
+//! The sitemap is made from `sitemap/<post_id>`, which `Post::index_sitemap` keeps up to date as posts are written, so only what's asked for is read.
+//! Past `SITEMAP_LEN` posts, `sitemap.xml` is an index of `sitemap/<first post_id>.xml` files, each listing that many posts.
+
+
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::posts_api::site_url;
+use crate::posts_store::Database;
+
+use serde::Deserialize;
+use serde_json::from_str;
+
+
+
+/// The most URLs that one sitemap file may list.
+const SITEMAP_LEN: usize = 50_000;
+
+/// The paths that crawlers are asked to keep out of, unless `ROBOTS_TXT` says otherwise: forms, actions and per-user pages.
+const DISALLOWED: [&str; 18] = ["/login", "/new", "/edit", "/move", "/publish", "/reward", "/save", "/saved", "/subscribe", "/follow", "/feed", "/notifications", "/events", "/webhook", "/webmention", "/ap", "/takeout", "/delete_account"];
+
+
+
+/// A post's entry in the sitemap.
+#[derive(Deserialize)]
+struct Entry {
+    url: String,
+    modified: i64,
+}
+
+
+
+/// Makes `robots.txt`: the rules in the `ROBOTS_TXT` environment variable (by default, to keep out of `DISALLOWED`), and where the sitemap is.
+pub fn robots() -> String {
+    robots_txt(std::env::var("ROBOTS_TXT").ok(), &site_url())
+}
+/// Makes `robots.txt` out of its rules (`None` for the default ones) and the site's address.
+fn robots_txt(rules: Option<String>, site: &str) -> String {
+    let rules = rules.unwrap_or_else(|| {
+        "User-agent: *\n".to_owned() + &DISALLOWED.iter().map(|path| format!("Disallow: {}\n", path)).collect::<String>()
+    });
+    format!("{}\nSitemap: {}/sitemap.xml\n", rules.trim_end(), site)
+}
+
+/// Makes `sitemap.xml`: every listed post, or (if there are too many) an index of files that each list some.
+pub fn index(data: &Database) -> Option<String> {
+    let r = data.at(&["sitemap"]).ok()?.with_params().shallow(true).get().ok()?;
+    let mut ids: Vec<String> = from_str::<Option<BTreeMap<String, bool>>>(&r.body).ok()?.unwrap_or_default().into_keys().collect();
+    if ids.len() <= SITEMAP_LEN { return page(data, None) };
+    ids.sort(); // As Firebase orders keys, so that each file starts where the last ended.
+    let site = site_url();
+    let sitemaps: String = ids.iter().step_by(SITEMAP_LEN).map(|id| format!("<sitemap><loc>{}/sitemap/{}.xml</loc></sitemap>\n", escape(&site), id)).collect();
+    Some(format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<sitemapindex xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n{}</sitemapindex>\n", sitemaps))
+}
+
+/// Makes one sitemap file: `SITEMAP_LEN` posts from the one with ID `start` (as stored, so `"_"` for the root post), or all of them if `None`.
+pub fn page(data: &Database, start: Option<&str>) -> Option<String> {
+    if let Some(start) = start {
+        if start.is_empty() || !start.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') { return None };
+    }
+    let mut n = data.at(&["sitemap"]).ok()?.with_params();
+    if let Some(start) = start {
+        let url = Arc::get_mut(&mut n.url).unwrap();
+        url.set_query(Some(&format!("orderBy=\"$key\"&startAt=\"{}\"&limitToFirst={}", start, SITEMAP_LEN)));
+    }
+    let r = n.get().ok()?;
+    let entries = from_str::<Option<BTreeMap<String, Entry>>>(&r.body).ok()?.unwrap_or_default();
+    let site = site_url();
+    let urls: String = entries.values().map(|e| {
+        let modified = chrono::NaiveDateTime::from_timestamp_opt(e.modified, 0).map(|d| d.format("%Y-%m-%d").to_string()).unwrap_or_default();
+        format!("<url><loc>{}</loc><lastmod>{}</lastmod></url>\n", escape(&(site.clone() + &e.url)), modified)
+    }).collect();
+    Some(format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n{}</urlset>\n", urls))
+}
+
+/// Escapes text for XML.
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_crawlers_out_of_actions() {
+        let robots = robots_txt(None, "https://a.example");
+        assert!(robots.starts_with("User-agent: *\nDisallow: /login\nDisallow: /new\n"));
+        assert!(robots.contains("\nDisallow: /webmention\n"));
+        assert!(robots.ends_with("Disallow: /delete_account\nSitemap: https://a.example/sitemap.xml\n"));
+    }
+
+    #[test]
+    fn takes_custom_rules() {
+        assert_eq!(robots_txt(Some("User-agent: *\nDisallow: /\n\n".to_owned()), "https://a.example"), "User-agent: *\nDisallow: /\nSitemap: https://a.example/sitemap.xml\n");
+    }
+
+    #[test]
+    fn escapes_urls() {
+        assert_eq!(escape("https://a.example/post/2021_a?key=1&b='<\">'"), "https://a.example/post/2021_a?key=1&amp;b=&apos;&lt;&quot;&gt;&apos;");
+    }
+}
\ No newline at end of file
@@ -0,0 +1,309 @@
+//! Tells other services (chat, CI and the like) about what happens to posts, by POSTing JSON to the URLs that the posts' authors registered.
+//!
+//! A post's webhooks hear of replies to it (`child`), its `edit`s, its `reward` changes and its `move`s to other parents; a `ping` can be sent to try one out.
+//! Each request is signed with the webhook's own secret: the `X-Signature-256` header is `sha256=` followed by the hex HMAC-SHA256 of the body.
+//! Deliveries are retried a few times, and the last `MAX_LOGGED` are logged under the post.
+//! They're made by `WORKERS` threads, from a queue of at most `MAX_QUEUED`: past that, events are dropped (and logged as such), rather than piling up.
+
+
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::posts_api::{new_uuid, signature, timestamp};
+use crate::posts_store::Database;
+use crate::outbound;
+
+use curl::easy::List;
+use firebase_rs::Firebase;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, json, Value};
+
+
+
+/// How many webhooks a post can have.
+pub const MAX_HOOKS: usize = 5;
+
+/// How long to wait before each try (the first one being right away).
+const RETRIES: [u64; 4] = [0, 10, 60, 600];
+
+/// How many deliveries are made at once.
+const WORKERS: usize = 4;
+
+/// How many deliveries can wait (for a worker, or to be retried) at once.
+const MAX_QUEUED: usize = 1000;
+
+/// How many deliveries are kept in each post's log.
+const MAX_LOGGED: usize = 50;
+
+
+
+/// A URL to notify, under `webhooks/<post_id>/<hook_id>`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Hook {
+    #[serde(skip)]
+    pub id: String,
+    pub url: String,
+    pub secret: String, // Shown only to the post's author, to check signatures with.
+}
+
+/// What became of one event sent to one webhook, pushed to `webhook_deliveries/<post_id>`.
+#[derive(Serialize, Deserialize)]
+pub struct Delivery {
+    pub hook_id: String,
+    pub url: String,
+    pub event: String,
+    pub date: i64,
+    pub attempts: usize,
+    pub status: u32, // The HTTP status of the last try, or 0 if there was no response.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub error: String,
+}
+
+/// A delivery that's waiting to be tried, at `due`.
+struct Job {
+    due: Instant,
+    hook: Hook,
+    delivery_id: String,
+    delivery: Delivery,
+    body: String,
+    log: Option<Firebase>, // `webhook_deliveries/<post_id>`
+}
+
+/// The deliveries to make, and a signal for workers when one is added.
+#[derive(Default)]
+struct Queue {
+    jobs: Mutex<Vec<Job>>,
+    added: Condvar,
+}
+
+#[derive(Debug)]
+pub enum Rejection {
+    BadUrl,
+    TooMany,
+}
+impl ToString for Rejection {
+    fn to_string(&self) -> String {
+        match self {
+            Rejection::BadUrl => "The webhook's URL must start with http:// or https://, and be on the internet at large".to_owned(),
+            Rejection::TooMany => format!("A post can have at most {} webhooks", MAX_HOOKS),
+        }
+    }
+}
+
+
+
+/// Registers a webhook on a post. Only its author should be allowed to.
+pub fn add(data: &Database, post_id: &str, url: &str) -> Result<Hook, Rejection> {
+    let url = url.trim();
+    if !(url.starts_with("http://") || url.starts_with("https://")) || url.len() > 2000 || url.contains(char::is_whitespace) || outbound::open(url).is_err() {
+        return Err(Rejection::BadUrl)
+    }
+    if hooks(data, post_id).len() >= MAX_HOOKS { return Err(Rejection::TooMany) };
+    let hook = Hook{ id: new_uuid(), url: url.to_owned(), secret: new_uuid() };
+    data.at(&["webhooks", post_id, &hook.id]).ok().map(|n| n.set(&json!(hook).to_string()).ok());
+    Ok(hook)
+}
+
+/// Unregisters a webhook.
+pub fn remove(data: &Database, post_id: &str, hook_id: &str) {
+    data.at(&["webhooks", post_id, hook_id]).ok().map(|n| n.delete("").ok());
+}
+
+/// Forgets all of a post's webhooks and their deliveries.
+pub fn remove_all(data: &Database, post_id: &str) {
+    data.at(&["webhooks", post_id]).ok().map(|n| n.delete("").ok());
+    data.at(&["webhook_deliveries", post_id]).ok().map(|n| n.delete("").ok());
+}
+
+/// Gets a post's webhooks.
+pub fn hooks(data: &Database, post_id: &str) -> Vec<Hook> {
+    let r = data.at(&["webhooks", post_id]).ok().map(|n| n.get().ok()).flatten();
+    let hooks = r.map(|r| from_str::<HashMap<String, Hook>>(&r.body).ok()).flatten().unwrap_or_default();
+    hooks.into_iter().map(|(id, hook)| Hook{ id, ..hook }).collect()
+}
+
+/// Gets the last deliveries to a post's webhooks, newest first.
+pub fn deliveries(data: &Database, post_id: &str, limit: usize) -> Vec<Delivery> {
+    let response = data.at(&["webhook_deliveries", post_id]).ok().map(|n| {
+        let mut n = n.with_params();
+        let url = std::sync::Arc::get_mut(&mut n.url).unwrap();
+        url.set_query(Some(&format!("orderBy={}&limitToLast={}", "\"$key\"", limit)));
+        n.get().ok()
+    }).flatten();
+    let deliveries = response.map(|r| from_str::<BTreeMap<String, Delivery>>(&r.body).ok()).flatten().unwrap_or_default();
+    deliveries.into_values().rev().collect() // Push IDs sort by time.
+}
+
+/// Sends an event to all of a post's webhooks, in the background (retrying, then logging each delivery).
+/// `hook_id` limits it to one webhook (for pings).
+pub fn trigger(data: &Database, post_id: &str, event: &str, payload: Value, hook_id: Option<&str>) {
+    let hooks: Vec<Hook> = hooks(data, post_id).into_iter().filter(|h| hook_id.map(|id| id == h.id).unwrap_or(true)).collect();
+    if hooks.is_empty() { return };
+    let body = json!({
+        "event": event,
+        "post_id": post_id,
+        "date": timestamp(),
+        "data": payload,
+    }).to_string();
+    for hook in hooks {
+        let delivery = Delivery{ hook_id: hook.id.clone(), url: hook.url.clone(), event: event.to_owned(), date: timestamp(), attempts: 0, status: 0, error: String::new() };
+        let job = Job{ due: Instant::now(), hook, delivery_id: new_uuid(), delivery, body: body.clone(), log: data.at(&["webhook_deliveries", post_id]).ok() };
+        enqueue(job);
+    }
+}
+
+/// Adds a delivery to the queue (starting the workers, the first time), unless it's full.
+fn enqueue(mut job: Job) {
+    static QUEUE: OnceLock<&'static Queue> = OnceLock::new();
+    let queue = *QUEUE.get_or_init(|| {
+        let queue: &'static Queue = Box::leak(Box::default());
+        for _ in 0..WORKERS { std::thread::spawn(move || work(queue)); }
+        queue
+    });
+    let mut jobs = queue.jobs.lock().unwrap();
+    if jobs.len() >= MAX_QUEUED {
+        drop(jobs);
+        job.delivery.error = "Too many deliveries waiting, so this one was dropped".to_owned();
+        return log(job)
+    }
+    jobs.push(job);
+    queue.added.notify_one();
+}
+
+/// Makes deliveries as they come due, forever, putting failed ones back to be retried later.
+fn work(queue: &Queue) {
+    loop {
+        let mut jobs = queue.jobs.lock().unwrap();
+        let mut job = loop {
+            let now = Instant::now();
+            match jobs.iter().enumerate().min_by_key(|(_, j)| j.due).map(|(i, j)| (i, j.due)) {
+                Some((i, due)) if due <= now => break jobs.swap_remove(i),
+                Some((_, due)) => jobs = queue.added.wait_timeout(jobs, due - now).unwrap().0,
+                None => jobs = queue.added.wait(jobs).unwrap(),
+            }
+        };
+        drop(jobs);
+        job.delivery.attempts += 1;
+        match post(&job.hook, &job.delivery.event, &job.delivery_id, &job.body) {
+            Ok(status) => {
+                job.delivery.status = status;
+                job.delivery.error = String::new();
+            },
+            Err(e) => {
+                job.delivery.status = 0;
+                job.delivery.error = e;
+            },
+        }
+        match RETRIES.get(job.delivery.attempts) {
+            Some(wait) if job.delivery.status / 100 != 2 => {
+                job.due = Instant::now() + Duration::from_secs(*wait);
+                queue.jobs.lock().unwrap().push(job);
+                queue.added.notify_one();
+            },
+            _ => log(job),
+        }
+    }
+}
+
+/// Logs what became of a delivery, forgetting all but the last `MAX_LOGGED` of the post's.
+fn log(job: Job) {
+    let log = match job.log { Some(log) => log, None => return };
+    log.push(&json!(job.delivery).to_string()).ok();
+    let r = log.with_params().shallow(true).get().ok();
+    let mut ids: Vec<String> = r.map(|r| from_str::<HashMap<String, bool>>(&r.body).ok()).flatten().unwrap_or_default().into_keys().collect();
+    if ids.len() <= MAX_LOGGED { return };
+    ids.sort(); // Push IDs sort by time.
+    for id in &ids[..ids.len() - MAX_LOGGED] {
+        log.at(id).ok().map(|n| n.delete("").ok());
+    }
+}
+
+/// POSTs a signed body to a webhook, returning the HTTP status.
+fn post(hook: &Hook, event: &str, delivery_id: &str, body: &str) -> Result<u32, String> {
+    let mut headers = List::new();
+    let mut easy = outbound::open(&hook.url)?;
+    let e = |e: curl::Error| e.to_string();
+    headers.append("Content-Type: application/json").map_err(e)?;
+    headers.append("User-Agent: text_sharing_site-webhooks").map_err(e)?;
+    headers.append(&format!("X-Event: {}", event)).map_err(e)?;
+    headers.append(&format!("X-Delivery: {}", delivery_id)).map_err(e)?;
+    headers.append(&format!("X-Signature-256: sha256={}", signature(&hook.secret, body.as_bytes()))).map_err(e)?;
+    easy.post(true).map_err(e)?;
+    easy.post_fields_copy(body.as_bytes()).map_err(e)?;
+    easy.http_headers(headers).map_err(e)?;
+    easy.timeout(Duration::from_secs(10)).map_err(e)?;
+    easy.write_function(|response| Ok(response.len())).map_err(e)?; // Not interested, but not printing it either.
+    easy.perform().map_err(e)?;
+    easy.response_code().map_err(e)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+
+    /// A request's headers (lowercase names) and body.
+    type Request = (HashMap<String, String>, String);
+
+    /// A stand-in for a webhook's server: answers one request with `status`, and passes it on.
+    fn stand_in(status: u32) -> (String, Receiver<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (sender, received) = channel();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut headers = HashMap::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() { break };
+                if let Some((name, value)) = line.split_once(':') { headers.insert(name.trim().to_lowercase(), value.trim().to_owned()); }
+            }
+            let mut body = vec![0; headers.get("content-length").map(|l| l.parse().unwrap()).unwrap_or(0)];
+            reader.read_exact(&mut body).unwrap();
+            write!(&stream, "HTTP/1.1 {} Whatever\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok", status).unwrap();
+            sender.send((headers, String::from_utf8(body).unwrap())).unwrap();
+        });
+        (url, received)
+    }
+
+    fn hook(url: &str) -> Hook {
+        Hook{ id: "h".to_owned(), url: url.to_owned(), secret: "secret".to_owned() }
+    }
+
+    #[test]
+    fn posts_signed_events() {
+        let (url, received) = stand_in(200);
+        assert_eq!(post(&hook(&url), "edit", "d1", "{\"a\":1}"), Ok(200));
+        let (headers, body) = received.recv().unwrap();
+        assert_eq!(body, "{\"a\":1}");
+        assert_eq!(headers["x-event"], "edit");
+        assert_eq!(headers["x-delivery"], "d1");
+        assert_eq!(headers["content-type"], "application/json");
+        assert_eq!(headers["x-signature-256"], format!("sha256={}", signature("secret", body.as_bytes())));
+    }
+
+    #[test]
+    fn reports_failures() {
+        let (url, _received) = stand_in(500);
+        assert_eq!(post(&hook(&url), "ping", "d2", "{}"), Ok(500));
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap(); // Nothing listens there once it's dropped.
+        assert!(post(&hook(&format!("http://{}/", closed)), "ping", "d3", "{}").is_err());
+    }
+
+    #[test]
+    fn delivers_from_the_queue() {
+        let (url, received) = stand_in(200);
+        let delivery = Delivery{ hook_id: "h".to_owned(), url: url.clone(), event: "child".to_owned(), date: 0, attempts: 0, status: 0, error: String::new() };
+        enqueue(Job{ due: Instant::now(), hook: hook(&url), delivery_id: "d4".to_owned(), delivery, body: "{}".to_owned(), log: None });
+        let (headers, _) = received.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(headers["x-delivery"], "d4");
+    }
+}
\ No newline at end of file
@@ -0,0 +1,280 @@
+//! Sends and receives Webmentions (https://www.w3.org/TR/webmention/): notes that a page links to another.
+//!
+//! When a listed post links to pages elsewhere, those pages' Webmention endpoints are told. Pages elsewhere that link to a post's `/post/<url>` can tell our endpoint, `/webmention`, and are then shown under the post, apart from its replies.
+//! Both happen in the background, since they mean fetching other sites' pages (only public ones, even after redirects), and so `/webmention` only takes so many a minute.
+
+
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::outbound;
+use crate::posts_api::{content_hash, site_url, timestamp};
+use crate::posts_store::{Database, Resolved};
+
+use curl::easy::List;
+use iron::url::{form_urlencoded, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, json};
+
+
+
+/// How many links of one post are followed.
+const MAX_LINKS: usize = 20;
+
+/// The most that's read of another site's page.
+const MAX_PAGE: usize = 1024 * 1024;
+
+/// How many redirects are followed.
+const MAX_REDIRECTS: usize = 5;
+
+/// How long the limits on taking Webmentions are over: `MAX_PER_CLIENT` from each client, and `MAX_PER_WINDOW` from everyone.
+const WINDOW: Duration = Duration::from_secs(60);
+const MAX_PER_CLIENT: usize = 10;
+const MAX_PER_WINDOW: usize = 100;
+
+
+
+/// A response's headers, with lowercase names.
+type Headers = Vec<(String, String)>;
+
+/// When Webmentions were taken in the last `WINDOW`, and from whom.
+static RECENT: Mutex<Vec<(Instant, String)>> = Mutex::new(Vec::new());
+
+
+
+/// A page that links to a post, under `webmentions/<post_id>/<hash of its URL>`.
+#[derive(Serialize, Deserialize)]
+pub struct Mention {
+    pub source: String,
+    pub title: String, // The page's `<title>`, or "" if it has none.
+    pub date: i64, // When it was last verified.
+}
+
+
+
+/// Tells the pages that a post links to (in `links`, both those it links to now and those it no longer does) that it does, in the background.
+/// `url` is the post's path, such as `/post/2020_first_line`.
+pub fn send(url: &str, links: Vec<String>) {
+    let source = site_url() + url;
+    let targets: Vec<String> = links.into_iter().filter(|l| (l.starts_with("https://") || l.starts_with("http://")) && !l.starts_with(&site_url())).take(MAX_LINKS).collect();
+    if targets.is_empty() { return };
+    std::thread::spawn(move || {
+        for target in targets {
+            let r = endpoint(&target).map(|endpoint| {
+                let body = form_urlencoded::Serializer::new(String::new()).append_pair("source", &source).append_pair("target", &target).finish();
+                request(&endpoint, Some(&body))
+            });
+            if let Some(Err(e)) = r { eprintln!("Could not send a Webmention to {}: {}", target, e) };
+        }
+    });
+}
+
+/// Finds a page's Webmention endpoint: from its `Link` header, or else a `<link>` or `<a>` with `rel="webmention"`.
+fn endpoint(target: &str) -> Option<String> {
+    let (headers, page) = request(target, None).ok()?;
+    let base = Url::parse(target).ok()?;
+    let from_header = headers.iter().filter(|(name, _)| name == "link").flat_map(|(_, value)| value.split(',')).find_map(|link| {
+        let (url, params) = link.split_once(';')?;
+        let rels = params.split(';').find_map(|p| p.trim().strip_prefix("rel="))?.trim_matches('"');
+        if rels.split(' ').any(|r| r == "webmention") { Some(url.trim().trim_start_matches('<').trim_end_matches('>').to_owned()) } else { None }
+    });
+    let from_page = || page.split('<').skip(1).find_map(|tag| {
+        let tag = &tag[..tag.find('>')?];
+        if !(tag.starts_with("link ") || tag.starts_with("a ")) { return None };
+        let rels = attribute(tag, "rel")?;
+        if rels.split(' ').any(|r| r == "webmention") { attribute(tag, "href") } else { None }
+    });
+    let href = from_header.or_else(from_page)?;
+    base.join(&href).ok().map(|u| u.to_string()) // An empty `href` means the page itself.
+}
+
+/// Whether an HTML page has a tag (a link, mostly) whose `href` is exactly `target`.
+fn links_to(page: &str, target: &str) -> bool {
+    page.split('<').skip(1).any(|tag| {
+        let tag = tag[..tag.find('>').unwrap_or(tag.len())].replace(['\n', '\r', '\t'], " ");
+        attribute(&tag, "href").map(|href| href == target).unwrap_or(false)
+    })
+}
+
+/// The value of an HTML tag's attribute (without the `<` and `>`), quoted or not.
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let start = tag.find(&format!(" {}=", name))? + name.len() + 2;
+    let rest = &tag[start..];
+    let value = match rest.chars().next()? {
+        quote @ ('"' | '\'') => &rest[1..rest[1..].find(quote)? + 1],
+        _ => &rest[..rest.find(char::is_whitespace).unwrap_or(rest.len())],
+    };
+    Some(value.replace("&amp;", "&"))
+}
+
+
+
+/// Whether a client (by its address) may send a Webmention now, counting it if so. Each one means fetching a page, so only so many are taken in a `WINDOW`.
+pub fn admit(client: &str) -> bool {
+    let mut recent = RECENT.lock().unwrap();
+    let now = Instant::now();
+    recent.retain(|(at, _)| now.duration_since(*at) < WINDOW);
+    if recent.len() >= MAX_PER_WINDOW || recent.iter().filter(|(_, c)| c == client).count() >= MAX_PER_CLIENT { return false };
+    recent.push((now, client.to_owned()));
+    true
+}
+
+/// Takes a Webmention from elsewhere: checks that `target` is a post here, and then (in the background) that `source` links to it (with an `href` that's exactly `target`).
+/// If `source` is mentioned already but no longer links to it, it's forgotten.
+pub fn receive(data: &Database, source: &str, target: &str) -> Result<(), String> {
+    if !(source.starts_with("https://") || source.starts_with("http://")) || source.len() > 2000 { return Err("The source must be a web address".to_owned()) };
+    let path = target.strip_prefix(&site_url()).map(|p| p.split(['?', '#']).next().unwrap_or("")).unwrap_or("");
+    let url = path.strip_prefix("/post/").filter(|u| !u.is_empty() && !u.contains('/')).ok_or("The target must be a post here")?;
+    let post_id = match data.resolve_url(url) {
+        Some(Resolved::Post(id)) => id,
+        Some(Resolved::Moved(moved_to)) => match data.resolve_url(&moved_to) {
+            Some(Resolved::Post(id)) => id,
+            _ => return Err("No such post".to_owned()),
+        },
+        None => url.to_owned(),
+    };
+    match data.read(vec![&post_id]).pop().flatten() {
+        Some(post) if post.visible_to("", "") => (), // Not drafts nor private posts.
+        _ => return Err("No such post".to_owned()),
+    }
+    let node = data.at(&["webmentions", &post_id, &content_hash(source.as_bytes())]).ok();
+    let (source, target) = (source.to_owned(), target.to_owned());
+    std::thread::spawn(move || {
+        match request(&source, None) {
+            Ok((_, page)) if links_to(&page, &target) => {
+                let title = page.split_once("<title>").map(|(_, t)| t.split_once("</title>").map(|(t, _)| t.trim().to_owned())).flatten().unwrap_or_default();
+                let mention = Mention{ source, title, date: timestamp() };
+                node.map(|n| n.set(&json!(mention).to_string()).ok());
+            },
+            Ok(_) => { node.map(|n| n.delete("").ok()); },
+            Err(e) => eprintln!("Could not verify a Webmention from {}: {}", source, e),
+        }
+    });
+    Ok(())
+}
+
+/// Gets the pages that link to a post, newest first.
+pub fn mentions(data: &Database, post_id: &str) -> Vec<Mention> {
+    let r = data.at(&["webmentions", post_id]).ok().map(|n| n.get().ok()).flatten();
+    let mut mentions: Vec<Mention> = r.map(|r| from_str::<HashMap<String, Mention>>(&r.body).ok()).flatten().unwrap_or_default().into_values().collect();
+    mentions.sort_by_key(|m| -m.date);
+    mentions
+}
+
+
+
+/// GETs (or, with a form `body`, POSTs) to another site, returning the response's headers (lowercase names) and body (as text).
+/// GETs follow redirects, each of which must lead somewhere public too.
+fn request(url: &str, body: Option<&str>) -> Result<(Headers, String), String> {
+    let mut url = url.to_owned();
+    for _ in 0..=MAX_REDIRECTS {
+        let (status, headers, page) = request_once(&url, body)?;
+        let location = headers.iter().find(|(name, _)| name == "location").map(|(_, value)| value);
+        match location {
+            Some(location) if body.is_none() && status / 100 == 3 => url = Url::parse(&url).and_then(|u| u.join(location)).map_err(|e| e.to_string())?.to_string(),
+            _ if status / 100 != 2 => return Err(format!("{} answered {}", url, status)),
+            _ => return Ok((headers, page)),
+        }
+    }
+    Err(format!("Too many redirects, to {}", url))
+}
+/// Makes one request, returning the response's status, headers and body.
+fn request_once(url: &str, body: Option<&str>) -> Result<(u32, Headers, String), String> {
+    let e = |e: curl::Error| e.to_string();
+    let mut easy = outbound::open(url)?;
+    let mut list = List::new();
+    list.append("User-Agent: text_sharing_site-webmention").map_err(e)?;
+    if let Some(body) = body {
+        list.append("Content-Type: application/x-www-form-urlencoded").map_err(e)?;
+        easy.post(true).map_err(e)?;
+        easy.post_fields_copy(body.as_bytes()).map_err(e)?;
+    }
+    easy.http_headers(list).map_err(e)?;
+    easy.timeout(Duration::from_secs(10)).map_err(e)?;
+    let mut headers = vec![];
+    let mut page = Vec::new();
+    {
+        let mut transfer = easy.transfer();
+        transfer.header_function(|header| {
+            let header = String::from_utf8_lossy(header);
+            if header.starts_with("HTTP/") { headers.clear() }; // A `100 Continue`'s headers are of no interest.
+            if let Some((name, value)) = header.split_once(':') { headers.push((name.trim().to_lowercase(), value.trim().to_owned())) };
+            true
+        }).map_err(e)?;
+        transfer.write_function(|chunk| {
+            if page.len() + chunk.len() > MAX_PAGE { return Ok(0) }; // Aborts.
+            page.extend_from_slice(chunk);
+            Ok(chunk.len())
+        }).map_err(e)?;
+        transfer.perform().map_err(e)?;
+    }
+    let status = easy.response_code().map_err(e)?;
+    Ok((status, headers, String::from_utf8_lossy(&page).into_owned()))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    /// A stand-in for another site: answers one GET with `response` (its status line, headers and body).
+    fn stand_in(response: String) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/page", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 { line.clear() };
+            write!(&stream, "{}", response).unwrap();
+        });
+        url
+    }
+
+    fn redirect(to: &str) -> String {
+        format!("HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", to)
+    }
+
+    #[test]
+    fn follows_redirects() {
+        let page = stand_in("HTTP/1.1 200 OK\r\nLink: </wm>; rel=\"webmention\"\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello".to_owned());
+        let (headers, body) = request(&stand_in(redirect(&page)), None).unwrap();
+        assert_eq!(body, "hello");
+        assert!(headers.contains(&("link".to_owned(), "</wm>; rel=\"webmention\"".to_owned())));
+    }
+
+    #[test]
+    fn refuses_redirects_inside() {
+        let r = request(&stand_in(redirect("http://169.254.169.254/latest/meta-data/")), None);
+        assert!(r.unwrap_err().starts_with("Not a public address"));
+    }
+
+    #[test]
+    fn finds_attributes() {
+        assert_eq!(attribute("link rel=\"webmention\" href='/wm?a=1&amp;b=2'", "href"), Some("/wm?a=1&b=2".to_owned()));
+        assert_eq!(attribute("a href=/wm rel=webmention", "rel"), Some("webmention".to_owned()));
+        assert_eq!(attribute("a href=/wm", "rel"), None);
+    }
+
+    #[test]
+    fn finds_links_to_targets() {
+        let target = "https://example.com/post/a?b=1&c=2";
+        assert!(links_to("<p>See <a class=x\nhref=\"https://example.com/post/a?b=1&amp;c=2\">this</a>.</p>", target));
+        assert!(links_to("<link rel=mention href='https://example.com/post/a?b=1&c=2'>", target));
+        assert!(!links_to("<p>https://example.com/post/a?b=1&c=2</p>", target)); // Only mentioned.
+        assert!(!links_to("<a href=\"https://example.com/post/a?b=1&c=2x\">", target));
+        assert!(!links_to("<a data-href=\"https://example.com/post/a?b=1&c=2\">", target));
+    }
+
+    #[test]
+    fn limits_clients() {
+        for _ in 0..MAX_PER_CLIENT { assert!(admit("192.0.2.1")) };
+        assert!(!admit("192.0.2.1"));
+        assert!(admit("192.0.2.2"));
+    }
+}
\ No newline at end of file